@@ -0,0 +1,301 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::info;
+use serde_json::{json, Value};
+
+use crate::device_repo::find_device;
+use crate::dyn_device::DynDevice;
+use crate::error::AvaError;
+use crate::hall_lamp::HALL_LAMP;
+use crate::kitchen_lamp::KITCHEN_LAMP;
+use crate::kitchen_remote::KITCHEN_REMOTE;
+use crate::timers::{HALL_AUTO_OFF, TimerOp};
+
+// The clicks of a multi-click are closer than that
+const CLICK_WINDOW: Duration = Duration::from_millis(400);
+// One brightness step every period while the button is held
+const RAMP_PERIOD: Duration = Duration::from_millis(200);
+const RAMP_STEP: i64 = 16;
+// A ramp stops by itself if the release never comes
+const RAMP_MAX_DURATION: Duration = Duration::from_secs(10);
+// The ramp down doesn't turn the lamp off
+const RAMP_MIN_BRIGHTNESS: i64 = 1;
+const RAMP_MAX_BRIGHTNESS: i64 = 254;
+
+pub (crate) fn build_action_bindings(device_repo: &HashMap<String, Arc<RefCell<dyn DynDevice>>>) -> Result<Vec<ActionBinding>, AvaError> {
+    let remote = find_device(device_repo, KITCHEN_REMOTE)?.as_ref().borrow().get_topic();
+    // The targets must exist as well
+    find_device(device_repo, KITCHEN_LAMP)?;
+    find_device(device_repo, HALL_LAMP)?;
+
+    let bind = |trigger, target| ActionBinding { remote: remote.clone(), trigger, target };
+    Ok(vec![
+        bind(ActionTrigger::Action("on"), ActionTarget::Loop { device: KITCHEN_LAMP, payload: json!({"state": "ON"}) }),
+        bind(ActionTrigger::Action("off"), ActionTarget::Loop { device: KITCHEN_LAMP, payload: json!({"state": "OFF"}) }),
+        // Double click on, all the lights at full brightness
+        bind(ActionTrigger::Clicks("on", 2), ActionTarget::Scene(vec![
+            (KITCHEN_LAMP, json!({"state": "ON", "brightness": 254})),
+            (HALL_LAMP, json!({"state": "ON", "brightness": 254})),
+        ])),
+        // Double click off, the hall lamp goes off in a while
+        bind(ActionTrigger::Clicks("off", 2), ActionTarget::Timer { name: HALL_AUTO_OFF, op: TimerOp::Start }),
+        bind(ActionTrigger::Action("brightness_move_up"), ActionTarget::RampStart { device: KITCHEN_LAMP, step: RAMP_STEP }),
+        bind(ActionTrigger::Action("brightness_move_down"), ActionTarget::RampStart { device: KITCHEN_LAMP, step: -RAMP_STEP }),
+        bind(ActionTrigger::Action("brightness_stop"), ActionTarget::RampStop),
+    ])
+}
+
+///
+/// The brightness after one step of the ramp, none when the limit is already reached
+///
+pub (crate) fn ramp_brightness(current: i64, step: i64) -> Option<i64> {
+    let brightness = (current + step).clamp(RAMP_MIN_BRIGHTNESS, RAMP_MAX_BRIGHTNESS);
+    if brightness == current {
+        None
+    } else {
+        Some(brightness)
+    }
+}
+
+///
+/// What an action of a remote must look like to trigger a binding
+///
+#[derive(Debug, Clone, PartialEq)]
+pub (crate) enum ActionTrigger {
+    // The action as sent by the remote
+    Action(&'static str),
+    // The action repeated that many times within the click window, counted by AVA
+    Clicks(&'static str, u32),
+}
+
+///
+/// What AVA does on an action
+///
+#[derive(Debug, Clone, PartialEq)]
+pub (crate) enum ActionTarget {
+    // Set the device to the state and carry it to the other devices of its loops
+    Loop { device: &'static str, payload: Value },
+    // Set each device to its state, without the loops
+    Scene(Vec<(&'static str, Value)>),
+    Timer { name: &'static str, op: TimerOp },
+    // Move the brightness of the device (and of its loops) step by step until the stop
+    RampStart { device: &'static str, step: i64 },
+    RampStop,
+    // A step of the running ramp, produced by AVA
+    RampStep { device: &'static str, step: i64 },
+}
+
+#[derive(Debug, Clone)]
+pub (crate) struct ActionBinding {
+    // Topic of the remote
+    pub remote: String,
+    pub trigger: ActionTrigger,
+    pub target: ActionTarget,
+}
+
+#[derive(Debug, Clone)]
+struct Ramp {
+    device: &'static str,
+    step: i64,
+    // Epoch millis
+    next_at: u64,
+    until: u64,
+}
+
+///
+/// The actions of the remotes turned into targets.
+/// An action with a multi-click binding waits for the end of its click window, the others are immediate.
+///
+#[derive(Debug)]
+pub (crate) struct Actions {
+    bindings: Vec<ActionBinding>,
+    // Number of clicks and end of the window, by remote topic and action
+    clicks: HashMap<(String, String), (u32, u64)>,
+    ramp: Option<Ramp>,
+}
+
+impl Actions {
+    pub (crate) fn new(bindings: Vec<ActionBinding>) -> Self {
+        Self {
+            bindings,
+            clicks: HashMap::new(),
+            ramp: None,
+        }
+    }
+
+    /// The topics of the remotes, each once
+    pub (crate) fn remotes(&self) -> Vec<String> {
+        let mut remotes: Vec<String> = vec![];
+        for b in &self.bindings {
+            if !remotes.contains(&b.remote) {
+                remotes.push(b.remote.clone());
+            }
+        }
+        remotes
+    }
+
+    ///
+    /// The targets of the action, empty while its clicks are being counted
+    ///
+    pub (crate) fn on_action(&mut self, remote: &str, action: &str, now: u64) -> Vec<ActionTarget> {
+        let counted = self.bindings.iter().any(|b| b.remote == remote && matches!(b.trigger, ActionTrigger::Clicks(a, _) if a == action));
+        if counted {
+            let entry = self.clicks.entry((remote.to_string(), action.to_string())).or_insert((0, 0));
+            entry.0 += 1;
+            entry.1 = now + CLICK_WINDOW.as_millis() as u64;
+            info!("🖱 Click [{}] of [{}] on [{}]", entry.0, action, remote);
+            return vec![];
+        }
+        self.bindings.iter()
+            .filter(|b| b.remote == remote && matches!(b.trigger, ActionTrigger::Action(a) if a == action))
+            .map(|b| b.target.clone())
+            .collect()
+    }
+
+    pub (crate) fn start_ramp(&mut self, device: &'static str, step: i64, now: u64) {
+        info!("🎚 Start the ramp of [{}], step {}", device, step);
+        self.ramp = Some(Ramp { device, step, next_at: now, until: now + RAMP_MAX_DURATION.as_millis() as u64 });
+    }
+
+    pub (crate) fn stop_ramp(&mut self) {
+        if let Some(ramp) = self.ramp.take() {
+            info!("🎚 Stop the ramp of [{}]", ramp.device);
+        }
+    }
+
+    /// Time left before the end of a click window or the next step of the ramp
+    pub (crate) fn next_delay(&self, now: u64) -> Option<Duration> {
+        self.clicks.values().map(|(_, until)| *until)
+            .chain(self.ramp.as_ref().map(|r| r.next_at))
+            .min()
+            .map(|at| Duration::from_millis(at.saturating_sub(now)))
+    }
+
+    ///
+    /// The targets of the click windows that are over, and the next step of the ramp
+    ///
+    pub (crate) fn take_due(&mut self, now: u64) -> Vec<ActionTarget> {
+        let mut due = vec![];
+        let over: Vec<(String, String)> = self.clicks.iter().filter(|(_, (_, until))| *until <= now).map(|(k, _)| k.clone()).collect();
+        for (remote, action) in over {
+            let count = self.clicks.remove(&(remote.clone(), action.clone())).map_or(0, |(c, _)| c);
+            let action: &str = &action;
+            info!("🖱 [{}] x{} on [{}]", action, count, &remote);
+            for b in self.bindings.iter().filter(|b| b.remote == remote) {
+                let matched = match b.trigger {
+                    ActionTrigger::Clicks(a, n) => a == action && n == count,
+                    // A single click is the plain action
+                    ActionTrigger::Action(a) => a == action && count == 1,
+                };
+                if matched {
+                    due.push(b.target.clone());
+                }
+            }
+        }
+
+        if let Some(ramp) = &mut self.ramp {
+            if now >= ramp.until {
+                self.stop_ramp();
+            } else if now >= ramp.next_at {
+                ramp.next_at = now + RAMP_PERIOD.as_millis() as u64;
+                due.push(ActionTarget::RampStep { device: ramp.device, step: ramp.step });
+            }
+        }
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REMOTE: &str = "zigbee2mqtt/kitchen_remote";
+    const LAMP: &str = "kitchen_lamp";
+
+    fn actions() -> Actions {
+        let bind = |trigger, target| ActionBinding { remote: REMOTE.to_string(), trigger, target };
+        Actions::new(vec![
+            bind(ActionTrigger::Action("on"), ActionTarget::Loop { device: LAMP, payload: json!({"state": "ON"}) }),
+            bind(ActionTrigger::Clicks("on", 2), ActionTarget::Scene(vec![])),
+            bind(ActionTrigger::Action("off"), ActionTarget::Loop { device: LAMP, payload: json!({"state": "OFF"}) }),
+            bind(ActionTrigger::Action("brightness_stop"), ActionTarget::RampStop),
+        ])
+    }
+
+    fn window() -> u64 {
+        CLICK_WINDOW.as_millis() as u64
+    }
+
+    #[test]
+    fn plain_action_is_immediate() {
+        let mut actions = actions();
+        assert_eq!(actions.on_action(REMOTE, "off", 1_000), vec![ActionTarget::Loop { device: LAMP, payload: json!({"state": "OFF"}) }]);
+        assert!(actions.on_action(REMOTE, "unbound", 1_000).is_empty());
+        assert!(actions.on_action("zigbee2mqtt/other_remote", "off", 1_000).is_empty());
+        assert_eq!(actions.next_delay(1_000), None);
+    }
+
+    #[test]
+    fn single_click_at_the_end_of_its_window() {
+        let mut actions = actions();
+        assert!(actions.on_action(REMOTE, "on", 1_000).is_empty());
+        assert_eq!(actions.next_delay(1_000), Some(CLICK_WINDOW));
+        assert!(actions.take_due(1_000 + window() - 1).is_empty());
+        assert_eq!(actions.take_due(1_000 + window()), vec![ActionTarget::Loop { device: LAMP, payload: json!({"state": "ON"}) }]);
+        assert_eq!(actions.next_delay(1_000 + window()), None);
+    }
+
+    #[test]
+    fn clicks_within_the_window_add_up() {
+        let mut actions = actions();
+        actions.on_action(REMOTE, "on", 1_000);
+        actions.on_action(REMOTE, "on", 1_300);
+        // The second click extends the window
+        assert!(actions.take_due(1_000 + window()).is_empty());
+        assert_eq!(actions.take_due(1_300 + window()), vec![ActionTarget::Scene(vec![])]);
+
+        // Nothing bound to 3 clicks
+        actions.on_action(REMOTE, "on", 5_000);
+        actions.on_action(REMOTE, "on", 5_100);
+        actions.on_action(REMOTE, "on", 5_200);
+        assert!(actions.take_due(5_200 + window()).is_empty());
+    }
+
+    #[test]
+    fn ramp_steps_until_stopped() {
+        let mut actions = actions();
+        actions.start_ramp(LAMP, 16, 1_000);
+        let step = vec![ActionTarget::RampStep { device: LAMP, step: 16 }];
+        assert_eq!(actions.take_due(1_000), step);
+        assert!(actions.take_due(1_100).is_empty());
+        assert_eq!(actions.next_delay(1_100), Some(Duration::from_millis(100)));
+        assert_eq!(actions.take_due(1_200), step);
+        actions.stop_ramp();
+        assert_eq!(actions.next_delay(1_200), None);
+        assert!(actions.take_due(1_400).is_empty());
+    }
+
+    #[test]
+    fn ramp_brightness_stops_at_the_limits() {
+        assert_eq!(ramp_brightness(100, 16), Some(116));
+        assert_eq!(ramp_brightness(250, 16), Some(254));
+        assert_eq!(ramp_brightness(254, 16), None);
+        assert_eq!(ramp_brightness(10, -16), Some(1));
+        assert_eq!(ramp_brightness(1, -16), None);
+        // Off (no brightness), the ramp up starts from the bottom
+        assert_eq!(ramp_brightness(0, 16), Some(16));
+    }
+
+    #[test]
+    fn ramp_stops_by_itself_without_release() {
+        let mut actions = actions();
+        actions.start_ramp(LAMP, -16, 0);
+        let until = RAMP_MAX_DURATION.as_millis() as u64;
+        assert_eq!(actions.take_due(until - 1), vec![ActionTarget::RampStep { device: LAMP, step: -16 }]);
+        assert!(actions.take_due(until).is_empty());
+        assert_eq!(actions.next_delay(until), None);
+    }
+}
@@ -0,0 +1,89 @@
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use rumqttc::v5::AsyncClient;
+use rumqttc::v5::mqttbytes::QoS;
+use rumqttc::v5::mqttbytes::v5::LastWill;
+use serde_derive::*;
+
+use crate::error::ErrorCounters;
+
+pub (crate) const AVA_STATUS_TOPIC: &str = "ava/status";
+pub (crate) const AVA_HEARTBEAT_TOPIC: &str = "ava/heartbeat";
+pub (crate) const AVA_EVENT_TOPIC: &str = "ava/event";
+
+const ONLINE: &str = "online";
+const OFFLINE: &str = "offline";
+
+#[derive(Serialize, Debug)]
+struct Heartbeat {
+    status: String,
+    client_id: String,
+    uptime: u64,
+    errors: ErrorCounters,
+}
+
+///
+/// Tell the bus whether AVA is alive : retained online/offline status, last will and heartbeat.
+///
+#[derive(Debug, Clone)]
+pub (crate) struct AvaStatus {
+    pub client_id: String,
+    pub started: Instant,
+    pub heartbeat_interval: u64,
+}
+
+impl AvaStatus {
+    pub (crate) fn new(client_id: &str, heartbeat_interval: u64) -> Self {
+        Self {
+            client_id: client_id.to_string(),
+            started: Instant::now(),
+            heartbeat_interval,
+        }
+    }
+
+    /// The broker publishes it for us if the connection drops without a disconnect
+    pub (crate) fn last_will(&self) -> LastWill {
+        LastWill::new(AVA_STATUS_TOPIC, OFFLINE, QoS::AtLeastOnce, true, None)
+    }
+
+    pub (crate) fn heartbeat_period(&self) -> Option<Duration> {
+        match self.heartbeat_interval {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        }
+    }
+
+    pub (crate) fn publish_online(&self, client: &AsyncClient) {
+        info!("📣 AVA is {}", ONLINE);
+        if let Err(e) = client.try_publish(AVA_STATUS_TOPIC, QoS::AtLeastOnce, true, ONLINE) {
+            warn!("Cannot publish the online status, e={:?}", e);
+        }
+    }
+
+    pub (crate) fn publish_offline(&self, client: &AsyncClient) {
+        info!("📣 AVA is {}", OFFLINE);
+        if let Err(e) = client.try_publish(AVA_STATUS_TOPIC, QoS::AtLeastOnce, true, OFFLINE) {
+            warn!("Cannot publish the offline status, e={:?}", e);
+        }
+    }
+
+    pub (crate) fn publish_heartbeat(&self, client: &AsyncClient, errors: &ErrorCounters) {
+        let heartbeat = Heartbeat {
+            status: ONLINE.to_string(),
+            client_id: self.client_id.clone(),
+            uptime: self.started.elapsed().as_secs(),
+            errors: errors.clone(),
+        };
+        match serde_json::to_string(&heartbeat) {
+            Ok(payload) => {
+                if let Err(e) = client.try_publish(AVA_HEARTBEAT_TOPIC, QoS::AtMostOnce, false, payload) {
+                    warn!("Cannot publish the heartbeat, e={:?}", e);
+                }
+            }
+            Err(e) => {
+                warn!("Cannot build the heartbeat, e={}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,48 @@
+use serde_derive::*;
+
+pub (crate) const AVAILABILITY_SUFFIX: &str = "/availability";
+
+///
+/// Availability of a zigbee2mqtt device, as published on <base>/<device>/availability
+///
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub (crate) enum Availability {
+    Unknown,
+    Online,
+    Offline,
+}
+
+#[derive(Deserialize, Debug)]
+struct AvailabilityMessage {
+    state: String,
+}
+
+impl Availability {
+
+    /// Read both the legacy payload (online) and the json one ({"state":"online"})
+    pub (crate) fn from_payload(msg: &str) -> Option<Self> {
+        let state = match serde_json::from_str::<AvailabilityMessage>(msg) {
+            Ok(m) => m.state,
+            Err(_) => msg.trim().to_string(),
+        };
+        match state.as_str() {
+            "online" => Some(Availability::Online),
+            "offline" => Some(Availability::Offline),
+            _ => None,
+        }
+    }
+
+    /// A device we never heard about is considered reachable
+    pub (crate) fn is_available(&self) -> bool {
+        *self != Availability::Offline
+    }
+}
+
+pub (crate) fn availability_topic(device_topic: &str) -> String {
+    format!("{}{}", device_topic, AVAILABILITY_SUFFIX)
+}
+
+/// zigbee2mqtt/hall_lamp/availability -> zigbee2mqtt/hall_lamp
+pub (crate) fn device_topic_of(topic: &str) -> Option<&str> {
+    topic.strip_suffix(AVAILABILITY_SUFFIX)
+}
@@ -0,0 +1,262 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+use log::{info, warn};
+use serde_derive::*;
+
+use crate::origin::Origin;
+use crate::outbound::OutboundQueue;
+use crate::policy::CommandPolicy;
+
+pub (crate) const AVA_ALERT_TOPIC: &str = "ava/alert";
+
+///
+/// When a loop or a device is considered out of control
+///
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub (crate) struct BreakerPolicy {
+    pub window: Duration,
+    // More propagations following AVA than that in the window is a storm, the human actions are not counted
+    pub max_propagations: usize,
+    // A device going back to the value it had 2 messages ago, that many times in the window, oscillates
+    pub max_flips: usize,
+    pub cooldown: Duration,
+}
+
+impl Default for BreakerPolicy {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_secs(10),
+            max_propagations: 20,
+            max_flips: 4,
+            cooldown: Duration::from_secs(60),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct Alert {
+    alert: String,
+    scope: String,
+    name: String,
+    reason: String,
+    cooldown: u64,
+}
+
+#[derive(Debug)]
+struct Breaker {
+    // Epoch millis and json of the recent messages
+    events: VecDeque<(u64, String)>,
+    // Epoch millis of the end of the suspension
+    open_until: Option<u64>,
+}
+
+impl Breaker {
+    fn new() -> Self {
+        Self {
+            events: VecDeque::new(),
+            open_until: None,
+        }
+    }
+
+    /// Record the message, return the reason when the breaker trips
+    fn record(&mut self, json: &str, now: u64, policy: &BreakerPolicy) -> Option<String> {
+        let window = policy.window.as_millis() as u64;
+        while self.events.front().is_some_and(|(at, _)| at + window < now) {
+            self.events.pop_front();
+        }
+        self.events.push_back((now, json.to_string()));
+
+        if self.events.len() > policy.max_propagations {
+            return Some(format!("{} messages in {}s", self.events.len(), policy.window.as_secs()));
+        }
+        let values: Vec<&String> = self.events.iter().map(|(_, v)| v).collect();
+        let flips = values.windows(3).filter(|w| w[0] == w[2] && w[0] != w[1]).count();
+        if flips >= policy.max_flips {
+            return Some(format!("{} value flips in {}s", flips, policy.window.as_secs()));
+        }
+        None
+    }
+}
+
+///
+/// Circuit breakers of the loops and of the devices.
+/// A tripped breaker suspends the loop (or ignores the device) until the cool-down is over.
+///
+#[derive(Debug)]
+pub (crate) struct CircuitBreakers {
+    policy: BreakerPolicy,
+    loops: HashMap<String, Breaker>,
+    devices: HashMap<String, Breaker>,
+}
+
+impl CircuitBreakers {
+    pub (crate) fn new(policy: BreakerPolicy) -> Self {
+        Self {
+            policy,
+            loops: HashMap::new(),
+            devices: HashMap::new(),
+        }
+    }
+
+    pub (crate) fn allow_loop(&self, name: &str, now: u64) -> bool {
+        Self::allow(&self.loops, "loop", name, now)
+    }
+
+    pub (crate) fn allow_device(&self, topic: &str, now: u64) -> bool {
+        Self::allow(&self.devices, "device", topic, now)
+    }
+
+    /// The device was commanded by AVA recently enough for its messages to count, even if their echo is not recognized
+    pub (crate) fn follows_command(&self, commanded_at: Option<u64>, now: u64) -> bool {
+        commanded_at.is_some_and(|at| now < at + self.policy.window.as_millis() as u64)
+    }
+
+    ///
+    /// Close the breakers at the end of their cool-down, with a "reset" alert
+    ///
+    pub (crate) fn tick(&mut self, now: u64, outbound: &mut OutboundQueue, origin: &Origin) {
+        for (scope, breakers) in [("loop", &mut self.loops), ("device", &mut self.devices)] {
+            for (name, breaker) in breakers.iter_mut() {
+                if breaker.open_until.is_some_and(|until| until <= now) {
+                    info!("🔌 {} [{}] is active again", scope, name);
+                    breaker.open_until = None;
+                    breaker.events.clear();
+                    publish_alert(outbound, origin, Alert {
+                        alert: "reset".to_string(),
+                        scope: scope.to_string(),
+                        name: name.to_string(),
+                        reason: "cool-down over".to_string(),
+                        cooldown: 0,
+                    });
+                }
+            }
+        }
+    }
+
+    /// The message of the device, for the oscillation detection of the device
+    pub (crate) fn record_device(&mut self, topic: &str, json: &str, now: u64, outbound: &mut OutboundQueue, origin: &Origin) {
+        if let Some(alert) = Self::record(&mut self.devices, "device", topic, json, now, &self.policy) {
+            publish_alert(outbound, origin, alert);
+        }
+    }
+
+    /// A propagation through the loop, the json of the source tells the oscillations
+    pub (crate) fn record_loop(&mut self, name: &str, json: &str, now: u64, outbound: &mut OutboundQueue, origin: &Origin) {
+        if let Some(alert) = Self::record(&mut self.loops, "loop", name, json, now, &self.policy) {
+            publish_alert(outbound, origin, alert);
+        }
+    }
+
+    /// The breaker is closed by the tick at the end of the cool-down
+    fn allow(breakers: &HashMap<String, Breaker>, scope: &str, name: &str, now: u64) -> bool {
+        match breakers.get(name).and_then(|b| b.open_until) {
+            Some(until) if now < until => {
+                info!("🚧 {} [{}] is suspended", scope, name);
+                false
+            }
+            _ => true,
+        }
+    }
+
+    fn record(breakers: &mut HashMap<String, Breaker>, scope: &str, name: &str, json: &str, now: u64, policy: &BreakerPolicy) -> Option<Alert> {
+        let breaker = breakers.entry(name.to_string()).or_insert_with(Breaker::new);
+        if breaker.open_until.is_some() {
+            return None;
+        }
+        let reason = breaker.record(json, now, policy)?;
+        warn!("💥 {} [{}] tripped, {}, suspended for {}s", scope, name, &reason, policy.cooldown.as_secs());
+        breaker.open_until = Some(now + policy.cooldown.as_millis() as u64);
+        Some(Alert {
+            alert: "tripped".to_string(),
+            scope: scope.to_string(),
+            name: name.to_string(),
+            reason,
+            cooldown: policy.cooldown.as_secs(),
+        })
+    }
+}
+
+fn publish_alert(outbound: &mut OutboundQueue, origin: &Origin, alert: Alert) {
+    outbound.push_json(AVA_ALERT_TOPIC, &alert, &CommandPolicy::default(), origin);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> BreakerPolicy {
+        BreakerPolicy {
+            window: Duration::from_secs(10),
+            max_propagations: 5,
+            max_flips: 2,
+            cooldown: Duration::from_secs(60),
+        }
+    }
+
+    #[test]
+    fn messages_out_of_the_window_are_forgotten() {
+        let mut breaker = Breaker::new();
+        for i in 0..5 {
+            assert_eq!(breaker.record(&format!("{}", i), i * 1_000, &policy()), None);
+        }
+        // The first ones are more than 10s old
+        assert_eq!(breaker.record("5", 12_500, &policy()), None);
+        assert_eq!(breaker.events.len(), 3);
+    }
+
+    #[test]
+    fn too_many_messages_in_the_window_trip() {
+        let mut breaker = Breaker::new();
+        for i in 0..5 {
+            assert_eq!(breaker.record(&format!("{}", i), i * 100, &policy()), None);
+        }
+        assert_eq!(breaker.record("5", 600, &policy()), Some("6 messages in 10s".to_string()));
+    }
+
+    #[test]
+    fn back_and_forth_values_trip() {
+        let mut breaker = Breaker::new();
+        assert_eq!(breaker.record("on", 0, &policy()), None);
+        assert_eq!(breaker.record("off", 100, &policy()), None);
+        assert_eq!(breaker.record("on", 200, &policy()), None);
+        assert_eq!(breaker.record("off", 300, &policy()), Some("2 value flips in 10s".to_string()));
+    }
+
+    #[test]
+    fn a_steady_change_is_no_flip() {
+        let mut breaker = Breaker::new();
+        for (i, value) in ["10", "20", "30", "40", "50"].iter().enumerate() {
+            assert_eq!(breaker.record(value, i as u64 * 100, &policy()), None);
+        }
+    }
+
+    #[test]
+    fn tripped_breaker_suspends_until_the_end_of_the_cool_down() {
+        let mut breakers = CircuitBreakers::new(policy());
+        let mut outbound = OutboundQueue::new(10, None);
+        let origin = Origin::new_cause("test");
+        for (i, value) in ["on", "off", "on", "off"].iter().enumerate() {
+            breakers.record_loop("hall", value, i as u64 * 100, &mut outbound, &origin);
+        }
+        assert!(!breakers.allow_loop("hall", 1_000));
+        assert!(breakers.allow_loop("kitchen", 1_000));
+        // Still open, and nothing recorded meanwhile
+        breakers.tick(30_000, &mut outbound, &origin);
+        assert!(!breakers.allow_loop("hall", 30_000));
+        breakers.record_loop("hall", "on", 30_000, &mut outbound, &origin);
+        assert_eq!(breakers.loops["hall"].events.len(), 4);
+
+        breakers.tick(60_300, &mut outbound, &origin);
+        assert!(breakers.allow_loop("hall", 60_300));
+        assert!(breakers.loops["hall"].events.is_empty());
+    }
+
+    #[test]
+    fn only_the_recent_commands_count() {
+        let breakers = CircuitBreakers::new(policy());
+        assert!(breakers.follows_command(Some(1_000), 5_000));
+        assert!(!breakers.follows_command(Some(1_000), 11_000));
+        assert!(!breakers.follows_command(None, 5_000));
+    }
+}
@@ -0,0 +1,46 @@
+use log::{info, warn};
+
+use crate::availability::Availability;
+use crate::groups::GroupRegistry;
+
+pub (crate) const BRIDGE_STATE_TOPIC: &str = "zigbee2mqtt/bridge/state";
+
+///
+/// What AVA knows about the zigbee2mqtt bridge
+///
+#[derive(Debug, Clone)]
+pub (crate) struct Bridge {
+    pub state: Availability,
+    pub groups: GroupRegistry,
+}
+
+impl Bridge {
+    pub (crate) fn new() -> Self {
+        Self {
+            state: Availability::Unknown,
+            groups: GroupRegistry::default(),
+        }
+    }
+
+    pub (crate) fn is_online(&self) -> bool {
+        self.state == Availability::Online
+    }
+
+    ///
+    /// Store the state published on bridge/state, return true when the bridge is back online after being offline,
+    /// meaning zigbee2mqtt has been restarted and the devices must be initialized again.
+    ///
+    pub (crate) fn update(&mut self, msg: &str) -> bool {
+        let state = match Availability::from_payload(msg) {
+            None => {
+                warn!("Unknown bridge state, msg=<{}>", msg);
+                return false;
+            }
+            Some(s) => s
+        };
+        let restarted = self.state == Availability::Offline && state == Availability::Online;
+        info!("🌉 Bridge is {:?}", state);
+        self.state = state;
+        restarted
+    }
+}
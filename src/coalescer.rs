@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use log::info;
+
+use crate::device_message::merge_json;
+use crate::origin::Origin;
+
+///
+/// A message of a source kept for the end of its window
+///
+#[derive(Debug, Clone)]
+pub (crate) struct HeldMessage {
+    pub topic: String,
+    pub msg: String,
+    pub indexes: Vec<usize>,
+    pub origin: Origin,
+    // Tagged by an AVA instance
+    pub from_ava: bool,
+}
+
+#[derive(Debug)]
+struct Window {
+    length: Duration,
+    // Epoch millis
+    until: u64,
+    held: Option<HeldMessage>,
+}
+
+///
+/// Coalescing of the bursts of a source (ex : rotating the dimmer).
+/// The first message goes through right away and opens a window, the messages within the window are merged
+/// and only the latest state goes through at its end, which opens a new window.
+///
+#[derive(Debug, Default)]
+pub (crate) struct Coalescer {
+    windows: HashMap<String, Window>,
+}
+
+impl Coalescer {
+
+    ///
+    /// Keep the message when the window of its source is open, return false when it must be processed now
+    ///
+    pub (crate) fn hold(&mut self, message: &HeldMessage, length: Duration, now: u64) -> bool {
+        match self.windows.get_mut(&message.topic) {
+            Some(window) if now < window.until => {
+                let held = match window.held.take() {
+                    None => message.clone(),
+                    Some(mut held) => {
+                        // The messages may only carry the fields that changed
+                        held.msg = merge_json(&held.msg, &message.msg).unwrap_or_else(|_| message.msg.clone());
+                        for i in &message.indexes {
+                            if !held.indexes.contains(i) {
+                                held.indexes.push(*i);
+                            }
+                        }
+                        held.origin = message.origin.clone();
+                        held.from_ava = message.from_ava;
+                        held
+                    }
+                };
+                info!("🌊 Hold the message of [{}] until the end of its window", &message.topic);
+                window.held = Some(held);
+                true
+            }
+            _ => {
+                self.windows.insert(message.topic.clone(), Window { length, until: now + length.as_millis() as u64, held: None });
+                false
+            }
+        }
+    }
+
+    /// Time left before the end of the next window with a held message
+    pub (crate) fn next_delay(&self, now: u64) -> Option<Duration> {
+        self.windows.values()
+            .filter(|w| w.held.is_some())
+            .map(|w| w.until)
+            .min()
+            .map(|until| Duration::from_millis(until.saturating_sub(now)))
+    }
+
+    ///
+    /// The held messages whose window is over, a new window opens for each of them
+    ///
+    pub (crate) fn take_due(&mut self, now: u64) -> Vec<HeldMessage> {
+        let mut due = vec![];
+        self.windows.retain(|_, window| {
+            if now < window.until {
+                return true;
+            }
+            match window.held.take() {
+                None => false,
+                Some(held) => {
+                    due.push(held);
+                    window.until = now + window.length.as_millis() as u64;
+                    true
+                }
+            }
+        });
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIMMER: &str = "zigbee2mqtt/kitchen_inter_dim";
+    const WINDOW: Duration = Duration::from_millis(300);
+
+    fn message(msg: &str, index: usize, from_ava: bool) -> HeldMessage {
+        HeldMessage {
+            topic: DIMMER.to_string(),
+            msg: msg.to_string(),
+            indexes: vec![index],
+            origin: Origin::new_cause("test"),
+            from_ava,
+        }
+    }
+
+    fn value(json: &str) -> serde_json::Value {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn first_message_goes_through_and_opens_the_window() {
+        let mut coalescer = Coalescer::default();
+        assert!(!coalescer.hold(&message(r#"{"brightness":10}"#, 0, false), WINDOW, 1_000));
+        assert_eq!(coalescer.next_delay(1_000), None);
+        // Nothing held, the window closes without a message
+        assert!(coalescer.take_due(1_300).is_empty());
+        assert!(!coalescer.hold(&message(r#"{"brightness":20}"#, 0, false), WINDOW, 1_400));
+    }
+
+    #[test]
+    fn burst_is_merged_into_the_latest_state() {
+        let mut coalescer = Coalescer::default();
+        coalescer.hold(&message(r#"{"state":"ON","brightness":10}"#, 0, false), WINDOW, 1_000);
+        assert!(coalescer.hold(&message(r#"{"brightness":20}"#, 0, false), WINDOW, 1_100));
+        assert!(coalescer.hold(&message(r#"{"brightness":30}"#, 1, true), WINDOW, 1_200));
+        assert_eq!(coalescer.next_delay(1_200), Some(Duration::from_millis(100)));
+
+        assert!(coalescer.take_due(1_299).is_empty());
+        let due = coalescer.take_due(1_300);
+        assert_eq!(due.len(), 1);
+        assert_eq!(value(&due[0].msg), value(r#"{"brightness":30}"#));
+        assert_eq!(due[0].indexes, vec![0, 1]);
+        assert!(due[0].from_ava);
+    }
+
+    #[test]
+    fn end_of_a_window_opens_the_next_one() {
+        let mut coalescer = Coalescer::default();
+        coalescer.hold(&message(r#"{"brightness":10}"#, 0, false), WINDOW, 1_000);
+        coalescer.hold(&message(r#"{"brightness":20}"#, 0, false), WINDOW, 1_100);
+        assert_eq!(coalescer.take_due(1_300).len(), 1);
+        // Still within the new window
+        assert!(coalescer.hold(&message(r#"{"brightness":40}"#, 0, false), WINDOW, 1_500));
+        assert_eq!(coalescer.take_due(1_600).len(), 1);
+    }
+}
@@ -0,0 +1,124 @@
+use log::debug;
+use serde_json::Value;
+
+/// Fields that change on their own and say nothing about the state of the device
+const NOISE_FIELDS: &[&str] = &["linkquality", "update", "update_available", "last_seen"];
+
+///
+/// How two states of a device are compared : field by field, on the json of the typed message.
+/// A numeric field may move within its tolerance (brightness 200 vs 201), some fields are ignored.
+///
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub (crate) struct ComparisonPolicy {
+    pub tolerances: &'static [(&'static str, f64)],
+    pub ignored: &'static [&'static str],
+}
+
+impl Default for ComparisonPolicy {
+    fn default() -> Self {
+        Self {
+            tolerances: &[],
+            ignored: NOISE_FIELDS,
+        }
+    }
+}
+
+impl ComparisonPolicy {
+
+    pub (crate) fn with_tolerances(tolerances: &'static [(&'static str, f64)]) -> Self {
+        Self {
+            tolerances,
+            ..Self::default()
+        }
+    }
+
+    ///
+    /// True when the 2 json messages describe the same state.
+    /// Falls back to the string equality when one of them is not json (ex : empty last message).
+    ///
+    pub (crate) fn same_state(&self, incoming: &str, last: &str) -> bool {
+        match (serde_json::from_str::<Value>(incoming), serde_json::from_str::<Value>(last)) {
+            (Ok(a), Ok(b)) => self.same_value(None, &a, &b),
+            _ => incoming == last,
+        }
+    }
+
+    fn tolerance(&self, field: Option<&str>) -> f64 {
+        field.and_then(|f| self.tolerances.iter().find(|(name, _)| *name == f))
+            .map_or(0.0, |(_, t)| *t)
+    }
+
+    fn same_value(&self, field: Option<&str>, a: &Value, b: &Value) -> bool {
+        match (a, b) {
+            (Value::Object(oa), Value::Object(ob)) => {
+                let keys = oa.keys().chain(ob.keys()).filter(|k| !self.ignored.contains(&k.as_str()));
+                for key in keys {
+                    let same = match (oa.get(key), ob.get(key)) {
+                        (Some(va), Some(vb)) => self.same_value(Some(key), va, vb),
+                        _ => false,
+                    };
+                    if !same {
+                        debug!("Field [{}] differs", key);
+                        return false;
+                    }
+                }
+                true
+            }
+            (Value::Number(na), Value::Number(nb)) => {
+                match (na.as_f64(), nb.as_f64()) {
+                    (Some(fa), Some(fb)) => (fa - fb).abs() <= self.tolerance(field),
+                    _ => na == nb,
+                }
+            }
+            // Zigbee2mqtt is not consistent on the case of ON/OFF
+            (Value::String(sa), Value::String(sb)) => sa.eq_ignore_ascii_case(sb),
+            (Value::Array(va), Value::Array(vb)) => {
+                va.len() == vb.len() && va.iter().zip(vb.iter()).all(|(x, y)| self.same_value(field, x, y))
+            }
+            _ => a == b,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOLERANCES: &[(&str, f64)] = &[("brightness", 2.0), ("color_temp", 5.0)];
+
+    #[test]
+    fn tolerance_per_field() {
+        let policy = ComparisonPolicy::with_tolerances(TOLERANCES);
+        assert!(policy.same_state(r#"{"brightness":202}"#, r#"{"brightness":200}"#));
+        assert!(!policy.same_state(r#"{"brightness":203}"#, r#"{"brightness":200}"#));
+        assert!(policy.same_state(r#"{"color_temp":305}"#, r#"{"color_temp":300}"#));
+        // No tolerance for the other fields
+        assert!(!policy.same_state(r#"{"x":0.31}"#, r#"{"x":0.3}"#));
+        assert!(!ComparisonPolicy::default().same_state(r#"{"brightness":201}"#, r#"{"brightness":200}"#));
+    }
+
+    #[test]
+    fn missing_and_ignored_fields() {
+        let policy = ComparisonPolicy::default();
+        assert!(!policy.same_state(r#"{"state":"ON","brightness":200}"#, r#"{"state":"ON"}"#));
+        assert!(!policy.same_state(r#"{"state":"ON"}"#, r#"{"state":"ON","brightness":200}"#));
+        assert!(policy.same_state(r#"{"state":"ON","linkquality":54}"#, r#"{"state":"ON"}"#));
+        assert!(policy.same_state(r#"{"state":"ON","last_seen":"now"}"#, r#"{"state":"ON","last_seen":"before"}"#));
+    }
+
+    #[test]
+    fn case_of_the_strings() {
+        assert!(ComparisonPolicy::default().same_state(r#"{"state":"ON"}"#, r#"{"state":"on"}"#));
+    }
+
+    #[test]
+    fn not_objects() {
+        let policy = ComparisonPolicy::with_tolerances(TOLERANCES);
+        // Nothing stored yet
+        assert!(!policy.same_state(r#"{"state":"ON"}"#, ""));
+        assert!(policy.same_state("", ""));
+        assert!(policy.same_state("42", "42"));
+        assert!(!policy.same_state("42", "43"));
+        assert!(!policy.same_state(r#"[1,2]"#, r#"[1,2,3]"#));
+    }
+}
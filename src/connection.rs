@@ -0,0 +1,191 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use log::info;
+use rumqttc::{TlsConfiguration, Transport};
+use rumqttc::v5::MqttOptions;
+use rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore, ServerName};
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+
+use crate::env_param;
+use crate::error::AvaError;
+
+const MQTT_HOST: &str = "raspberrypi.local";
+const MQTT_USERNAME: &str = "ava";
+const MQTT_PASSWORD: &str = "avatece3.X";
+const WS_PATH: &str = "/mqtt";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransportKind {
+    Tcp,
+    Tls,
+    Ws,
+    Wss,
+}
+
+impl TransportKind {
+    fn from_name(name: &str) -> Result<Self, AvaError> {
+        match name.to_lowercase().as_str() {
+            "tcp" => Ok(TransportKind::Tcp),
+            "tls" => Ok(TransportKind::Tls),
+            "ws" => Ok(TransportKind::Ws),
+            "wss" => Ok(TransportKind::Wss),
+            other => Err(AvaError::Config(format!("unknown transport [{}], expected tcp, tls, ws or wss", other))),
+        }
+    }
+
+    fn default_port(&self) -> u16 {
+        match self {
+            TransportKind::Tcp => 1883,
+            TransportKind::Tls => 8883,
+            TransportKind::Ws => 8080,
+            TransportKind::Wss => 8081,
+        }
+    }
+}
+
+///
+/// How AVA reaches the broker : plain tcp, tls (optionally with a client certificate) or websocket.
+///
+#[derive(Debug, Clone)]
+pub struct ConnectionSettings {
+    pub host: String,
+    pub port: u16,
+    pub username: String,
+    pub password: String,
+    pub transport: TransportKind,
+    pub ws_path: String,
+    pub ca_file: Option<String>,
+    pub client_cert_file: Option<String>,
+    pub client_key_file: Option<String>,
+    // Name to check in the broker certificate when it differs from the host (ex : the broker is reached by its IP)
+    pub server_name: Option<String>,
+}
+
+impl ConnectionSettings {
+
+    pub (crate) fn from_env() -> Result<Self, AvaError> {
+        let transport = TransportKind::from_name(&env_param("AVA_MQTT_TRANSPORT", "tcp"))?;
+        let port = env_param("AVA_MQTT_PORT", &transport.default_port().to_string()).parse::<u16>()
+            .map_err(|e| AvaError::Config(format!("invalid AVA_MQTT_PORT, {}", e)))?;
+        let optional = |name: &str| {
+            let value = env_param(name, "");
+            if value.is_empty() { None } else { Some(value) }
+        };
+        Ok(Self {
+            host: env_param("AVA_MQTT_HOST", MQTT_HOST),
+            port,
+            username: env_param("AVA_MQTT_USERNAME", MQTT_USERNAME),
+            password: env_param("AVA_MQTT_PASSWORD", MQTT_PASSWORD),
+            transport,
+            ws_path: env_param("AVA_MQTT_WS_PATH", WS_PATH),
+            ca_file: optional("AVA_MQTT_CA_FILE"),
+            client_cert_file: optional("AVA_MQTT_CLIENT_CERT"),
+            client_key_file: optional("AVA_MQTT_CLIENT_KEY"),
+            server_name: optional("AVA_MQTT_SERVER_NAME"),
+        })
+    }
+
+    ///
+    /// Build the MqttOptions with the right transport and credentials
+    ///
+    pub (crate) fn mqtt_options(&self, client_id: &str) -> Result<MqttOptions, AvaError> {
+        info!("Connect to [{}:{}] with {:?}", &self.host, self.port, self.transport);
+        let mut mqttoptions = match self.transport {
+            TransportKind::Tcp | TransportKind::Tls => MqttOptions::new(client_id, &self.host, self.port),
+            // For the websockets, the broker address is the url
+            TransportKind::Ws => MqttOptions::new(client_id, format!("ws://{}:{}{}", &self.host, self.port, &self.ws_path), self.port),
+            TransportKind::Wss => MqttOptions::new(client_id, format!("wss://{}:{}{}", &self.host, self.port, &self.ws_path), self.port),
+        };
+        mqttoptions.set_credentials(&self.username, &self.password);
+
+        match self.transport {
+            TransportKind::Tcp => {}
+            TransportKind::Tls => {
+                mqttoptions.set_transport(Transport::tls_with_config(self.tls_configuration()?));
+            }
+            #[cfg(feature = "websocket")]
+            TransportKind::Ws => {
+                mqttoptions.set_transport(Transport::ws());
+            }
+            #[cfg(feature = "websocket")]
+            TransportKind::Wss => {
+                mqttoptions.set_transport(Transport::wss_with_config(self.tls_configuration()?));
+            }
+            #[cfg(not(feature = "websocket"))]
+            TransportKind::Ws | TransportKind::Wss => {
+                return Err(AvaError::Config("AVA is built without the websocket feature".to_string()));
+            }
+        }
+        Ok(mqttoptions)
+    }
+
+    fn tls_configuration(&self) -> Result<TlsConfiguration, AvaError> {
+        let ca_file = self.ca_file.as_ref().ok_or_else(|| AvaError::Config("TLS needs a CA file (AVA_MQTT_CA_FILE)".to_string()))?;
+        let mut roots = RootCertStore::empty();
+        let (added, _) = roots.add_parsable_certificates(&read_certificates(ca_file)?);
+        if added == 0 {
+            return Err(AvaError::Config(format!("no valid certificate in the CA file [{}]", ca_file)));
+        }
+
+        let server_name = match &self.server_name {
+            None => None,
+            Some(name) => {
+                info!("Check the broker certificate against [{}]", name);
+                Some(ServerName::try_from(name.as_str()).map_err(|e| AvaError::Config(format!("invalid server name [{}], {}", name, e)))?)
+            }
+        };
+        let builder = ClientConfig::builder().with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(ServerNameOverride {
+                inner: WebPkiVerifier::new(roots, None),
+                server_name,
+            }));
+
+        let config = match (&self.client_cert_file, &self.client_key_file) {
+            (Some(cert_file), Some(key_file)) => {
+                let certs = read_certificates(cert_file)?.into_iter().map(Certificate).collect();
+                builder.with_client_auth_cert(certs, read_private_key(key_file)?).map_err(|e| AvaError::Config(format!("invalid client certificate, {}", e)))?
+            }
+            (None, None) => builder.with_no_client_auth(),
+            _ => return Err(AvaError::Config("the client certificate and key go together (AVA_MQTT_CLIENT_CERT, AVA_MQTT_CLIENT_KEY)".to_string())),
+        };
+        Ok(TlsConfiguration::Rustls(Arc::new(config)))
+    }
+}
+
+fn read_certificates(path: &str) -> Result<Vec<Vec<u8>>, AvaError> {
+    let file = File::open(path).map_err(|e| AvaError::Config(format!("cannot open [{}], {}", path, e)))?;
+    rustls_pemfile::certs(&mut BufReader::new(file)).map_err(|e| AvaError::Config(format!("cannot read the certificates of [{}], {}", path, e)))
+}
+
+fn read_private_key(path: &str) -> Result<PrivateKey, AvaError> {
+    let file = File::open(path).map_err(|e| AvaError::Config(format!("cannot open [{}], {}", path, e)))?;
+    let items = rustls_pemfile::read_all(&mut BufReader::new(file)).map_err(|e| AvaError::Config(format!("cannot read the key of [{}], {}", path, e)))?;
+    for item in items {
+        match item {
+            rustls_pemfile::Item::RSAKey(key) | rustls_pemfile::Item::PKCS8Key(key) | rustls_pemfile::Item::ECKey(key) => {
+                return Ok(PrivateKey(key));
+            }
+            _ => {}
+        }
+    }
+    Err(AvaError::Config(format!("no private key in [{}]", path)))
+}
+
+///
+/// Usual webpki check of the broker certificate, against the overriding name if any instead of the host we connect to
+///
+struct ServerNameOverride {
+    inner: WebPkiVerifier,
+    server_name: Option<ServerName>,
+}
+
+impl ServerCertVerifier for ServerNameOverride {
+    fn verify_server_cert(&self, end_entity: &Certificate, intermediates: &[Certificate], server_name: &ServerName,
+                          scts: &mut dyn Iterator<Item = &[u8]>, ocsp_response: &[u8], now: SystemTime) -> Result<ServerCertVerified, rustls::Error> {
+        let server_name = self.server_name.as_ref().unwrap_or(server_name);
+        self.inner.verify_server_cert(end_entity, intermediates, server_name, scts, ocsp_response, now)
+    }
+}
@@ -0,0 +1,76 @@
+use std::collections::VecDeque;
+
+use log::warn;
+use serde_derive::*;
+
+use crate::error::AvaError;
+use crate::origin::Origin;
+use crate::outbound::{now_millis, OutboundQueue};
+use crate::policy::CommandPolicy;
+
+pub (crate) const DEADLETTER_TOPIC: &str = "ava/deadletter";
+
+/// Publish anything on it to get the last dead letters on <deadletter topic>/dump
+pub (crate) fn dump_request_topic(deadletter_topic: &str) -> String {
+    format!("{}/get", deadletter_topic)
+}
+
+fn dump_topic(deadletter_topic: &str) -> String {
+    format!("{}/dump", deadletter_topic)
+}
+
+///
+/// A message AVA could not process, with the reason
+///
+#[derive(Serialize, Debug, Clone)]
+pub (crate) struct DeadLetter {
+    pub topic: String,
+    pub payload: String,
+    pub error: String,
+    // The device that could not take the message, if known
+    pub device: Option<String>,
+    pub at: u64,
+}
+
+///
+/// Republish the failing messages on the dead-letter topic and keep the last ones for inspection
+///
+#[derive(Debug)]
+pub (crate) struct DeadLetterBox {
+    topic: String,
+    capacity: usize,
+    letters: VecDeque<DeadLetter>,
+}
+
+impl DeadLetterBox {
+    pub (crate) fn new(topic: &str, capacity: usize) -> Self {
+        Self {
+            topic: topic.to_string(),
+            capacity,
+            letters: VecDeque::new(),
+        }
+    }
+
+    pub (crate) fn post(&mut self, topic: &str, payload: &str, device: Option<&str>, e: &AvaError, outbound: &mut OutboundQueue, origin: &Origin) {
+        let letter = DeadLetter {
+            topic: topic.to_string(),
+            payload: payload.to_string(),
+            error: e.to_string(),
+            device: device.map(|d| d.to_string()),
+            at: now_millis(),
+        };
+        warn!("📮 Dead letter from [{}] for device {:?}, {}", topic, &letter.device, &letter.error);
+        outbound.push_json(&self.topic, &letter, &CommandPolicy::default(), origin);
+        if self.letters.len() >= self.capacity {
+            self.letters.pop_front();
+        }
+        if self.capacity > 0 {
+            self.letters.push_back(letter);
+        }
+    }
+
+    /// Publish the letters kept in memory, the oldest first
+    pub (crate) fn dump(&self, outbound: &mut OutboundQueue, origin: &Origin) {
+        outbound.push_json(&dump_topic(&self.topic), &self.letters, &CommandPolicy::default(), origin);
+    }
+}
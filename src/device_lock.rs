@@ -1,16 +1,38 @@
 use log::info;
 
+use crate::availability::Availability;
+use crate::manual_override::ManualOverride;
+use crate::outbound::now_millis;
+use crate::twin::DesiredState;
+
 #[derive(Debug, Clone)]
 pub (crate) struct DeviceLock<T> {
     pub count_locks : u32,
+    // States asked by the commands not echoed yet, oldest first
+    pub in_flight : Vec<String>,
+    // Epoch millis of the last command AVA sent to the device
+    pub commanded_at : Option<u64>,
     pub last_object_message : T,
+    pub availability : Availability,
+    // Last state sent by the device itself
+    pub reported : T,
+    // State AVA asked for and not reported yet
+    pub desired : Option<DesiredState>,
+    // Changed by hand, the loops leave it alone
+    pub manual_override : Option<ManualOverride>,
 }
 
-impl <T> DeviceLock<T> {
+impl <T: Clone> DeviceLock<T> {
     pub (crate) fn new(last_message: T) -> Self {
         Self {
             count_locks: 0,
-            last_object_message: last_message,
+            in_flight: vec![],
+            commanded_at: None,
+            last_object_message: last_message.clone(),
+            availability: Availability::Unknown,
+            reported: last_message,
+            desired: None,
+            manual_override: None,
         }
     }
 
@@ -23,12 +45,117 @@ impl <T> DeviceLock<T> {
         info!("⏬After down Locks:[{}]", self.count_locks);
     }
 
+    ///
+    /// A command asking for the state was sent, its echo is expected.
+    /// A command replacing one not sent yet gives a single echo, the state it asks for takes the place of the replaced one.
+    ///
+    pub (crate) fn expect_echo(&mut self, message: &str, replaced: bool) {
+        self.commanded_at = Some(now_millis());
+        if replaced && self.in_flight.pop().is_some() {
+            self.in_flight.push(message.to_string());
+            return;
+        }
+        self.inc();
+        self.in_flight.push(message.to_string());
+    }
+
+    ///
+    /// Take the report as the echo of the first command in flight whose state it matches.
+    /// The devices report in order, the commands sent before it will not be echoed anymore.
+    ///
+    pub (crate) fn take_echo(&mut self, is_echo: impl Fn(&str) -> bool) -> bool {
+        match self.in_flight.iter().position(|message| is_echo(message)) {
+            Some(index) => {
+                for _ in 0..=index {
+                    self.release_one();
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
     pub (crate) fn replace(&mut self, o : T) {
         self.last_object_message = o;
     }
 
+    /// The echo of one command will never come (ex : the command was dropped), the oldest is given up
+    pub (crate) fn release_one(&mut self) {
+        if self.count_locks > 0 {
+            self.dec();
+        }
+        if !self.in_flight.is_empty() {
+            self.in_flight.remove(0);
+        }
+    }
+
+    pub (crate) fn release_locks(&mut self) {
+        if self.count_locks > 0 {
+            info!("🔓 Release [{}] locks", self.count_locks);
+            self.count_locks = 0;
+        }
+        self.in_flight.clear();
+    }
+
+    /// An offline device will never send the echo of our commands, so its locks are released.
+    pub (crate) fn set_availability(&mut self, availability: Availability) {
+        if availability == Availability::Offline {
+            self.release_locks();
+            // It will be re-synced when it comes back
+            self.desired = None;
+        }
+        self.availability = availability;
+    }
+
 }
 
 // pub(crate) fn new(p0: String) -> _ {
 //     todo!()
-// }
\ No newline at end of file
+// }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lock_with(in_flight: &[&str]) -> DeviceLock<String> {
+        let mut dev_lock = DeviceLock::new("{}".to_string());
+        for message in in_flight {
+            dev_lock.expect_echo(message, false);
+        }
+        dev_lock
+    }
+
+    #[test]
+    fn echo_of_the_latest_command_takes_the_ones_before() {
+        let mut dev_lock = lock_with(&["A", "B", "C"]);
+        assert!(dev_lock.take_echo(|message| message == "B"));
+        assert_eq!(dev_lock.count_locks, 1);
+        assert_eq!(dev_lock.in_flight, vec!["C".to_string()]);
+    }
+
+    #[test]
+    fn report_matching_no_command_is_not_an_echo() {
+        let mut dev_lock = lock_with(&["A", "B"]);
+        assert!(!dev_lock.take_echo(|message| message == "X"));
+        assert_eq!(dev_lock.count_locks, 2);
+    }
+
+    #[test]
+    fn replacing_command_keeps_a_single_echo() {
+        let mut dev_lock = lock_with(&["A"]);
+        dev_lock.expect_echo("B", true);
+        assert_eq!(dev_lock.count_locks, 1);
+        assert_eq!(dev_lock.in_flight, vec!["B".to_string()]);
+    }
+
+    #[test]
+    fn release_gives_up_the_oldest() {
+        let mut dev_lock = lock_with(&["A", "B"]);
+        dev_lock.release_one();
+        assert_eq!(dev_lock.in_flight, vec!["B".to_string()]);
+        dev_lock.release_locks();
+        dev_lock.release_one();
+        assert_eq!(dev_lock.count_locks, 0);
+        assert!(dev_lock.in_flight.is_empty());
+    }
+}
@@ -1,6 +1,27 @@
 use log::info;
 use serde_derive::*;
 
+use crate::error::AvaError;
+
+///
+/// Merge the fields of an incoming payload into the stored state of the device.
+/// zigbee2mqtt and the dimmer may only send the fields that changed.
+///
+pub (crate) fn merge_json(last: &str, incoming: &str) -> Result<String, AvaError> {
+    let mut incoming_value: serde_json::Value = serde_json::from_str(incoming)?;
+    let last_value = serde_json::from_str::<serde_json::Value>(last);
+    match (last_value, incoming_value.as_object_mut()) {
+        (Ok(serde_json::Value::Object(mut merged)), Some(fields)) => {
+            for (key, value) in std::mem::take(fields) {
+                merged.insert(key, value);
+            }
+            Ok(serde_json::Value::Object(merged).to_string())
+        }
+        // Nothing stored yet (or not an object), the incoming payload is the whole state
+        _ => Ok(incoming.to_string()),
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
 pub (crate) struct LampColor {
     // pub hue: Option<u32>,
@@ -22,6 +43,10 @@ pub (crate) trait DeviceMessage {
     fn as_temp_sensor(&self) -> &'_ TempSensor{
         todo!()
     }
+    /// None for the messages of the devices with a state
+    fn as_action_event(&self) -> Option<&'_ ActionEvent> {
+        None
+    }
 
     fn to_json(&self) -> serde_json::error::Result<String>;
 
@@ -42,7 +67,9 @@ pub (crate) trait DeviceMessage {
     }
 }
 
+// All the fields are optional on the wire, the missing ones come from the stored state
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
 pub (crate) struct LampRGB {
     // There are 2 different modes : color xy for RGB and color temp for white lamps
     // pub color : LampColor,
@@ -68,8 +95,14 @@ impl LampRGB {
         }
     }
 
-    pub (crate) fn from_json(msg: &str) -> Result<Self, String> {
-        serde_json::from_str(msg).map_err(|e| e.to_string())
+    pub (crate) fn from_json(msg: &str) -> Result<Self, AvaError> {
+        Ok(serde_json::from_str(msg)?)
+    }
+}
+
+impl Default for LampRGB {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -110,6 +143,7 @@ impl DeviceMessage for LampRGB {
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
 pub (crate) struct InterSwitch {
     pub state: String,
 }
@@ -121,8 +155,14 @@ impl InterSwitch {
         }
     }
 
-    pub (crate) fn from_json(msg: &str) -> Result<Self, String> {
-        serde_json::from_str(msg).map_err(|e| e.to_string())
+    pub (crate) fn from_json(msg: &str) -> Result<Self, AvaError> {
+        Ok(serde_json::from_str(msg)?)
+    }
+}
+
+impl Default for InterSwitch {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -140,7 +180,7 @@ impl DeviceMessage for InterSwitch {
     }
 
     fn to_lamp_rgb(&self, last_message : &Box<dyn DeviceMessage>) -> Box<dyn DeviceMessage> {
-        info!("InterSwitch message conversion to Rgb : {}", &last_message.to_json().unwrap());
+        info!("InterSwitch message conversion to Rgb : {:?}", &last_message.to_json());
         let rgb = last_message.as_lamp_rgb();
         Box::new(LampRGB {
             color_temp: rgb.color_temp,
@@ -156,6 +196,7 @@ impl DeviceMessage for InterSwitch {
 }
 
 #[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(default)]
 pub (crate) struct TempSensor {
     pub battery : f32,
     pub humidity :f32,
@@ -175,8 +216,14 @@ impl TempSensor {
         }
     }
 
-    pub (crate) fn from_json(msg: &str) -> Result<Self, String> {
-        serde_json::from_str(msg).map_err(|e| e.to_string())
+    pub (crate) fn from_json(msg: &str) -> Result<Self, AvaError> {
+        Ok(serde_json::from_str(msg)?)
+    }
+}
+
+impl Default for TempSensor {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -191,6 +238,7 @@ impl DeviceMessage for TempSensor {
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
 pub (crate) struct InterDim {
     pub brightness:u16,
     // linkquality:u8,
@@ -205,8 +253,14 @@ impl InterDim {
         }
     }
 
-    pub (crate) fn from_json(msg: &str) -> Result<Self, String> {
-        serde_json::from_str(msg).map_err(|e| e.to_string())
+    pub (crate) fn from_json(msg: &str) -> Result<Self, AvaError> {
+        Ok(serde_json::from_str(msg)?)
+    }
+}
+
+impl Default for InterDim {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -225,7 +279,7 @@ impl DeviceMessage for InterDim {
     }
 
     fn to_lamp_rgb(&self, last_message : &Box<dyn DeviceMessage>) -> Box<dyn DeviceMessage> {
-        info!("InterDim message conversion to Rgb : {}", &last_message.to_json().unwrap());
+        info!("InterDim message conversion to Rgb : {:?}", &last_message.to_json());
         let rgb = last_message.as_lamp_rgb();
         dbg!(rgb);
         Box::new(LampRGB {
@@ -240,4 +294,77 @@ impl DeviceMessage for InterDim {
         serde_json::to_string(self)
     }
 
-}
\ No newline at end of file
+}
+
+///
+/// What a remote or a button publishes : an action (single, double, hold, brightness_move_up...).
+/// An action is an event, not a state : it's never stored nor compared.
+///
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub (crate) struct ActionEvent {
+    // zigbee2mqtt may publish null right after the action
+    pub action: Option<String>,
+}
+
+impl ActionEvent {
+    pub fn new() -> Self {
+        Self {
+            action: None
+        }
+    }
+
+    pub (crate) fn from_json(msg: &str) -> Result<Self, AvaError> {
+        Ok(serde_json::from_str(msg)?)
+    }
+}
+
+impl Default for ActionEvent {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DeviceMessage for ActionEvent {
+    fn as_action_event(&self) -> Option<&'_ ActionEvent> {
+        Some(self)
+    }
+
+    fn to_json(&self) -> serde_json::error::Result<String> {
+        serde_json::to_string(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(json: &str) -> serde_json::Value {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn merge_the_changed_fields() {
+        let merged = merge_json(r#"{"state":"ON","brightness":100,"color_temp":300}"#, r#"{"brightness":120}"#).unwrap();
+        assert_eq!(value(&merged), value(r#"{"state":"ON","brightness":120,"color_temp":300}"#));
+    }
+
+    #[test]
+    fn nothing_stored_yet() {
+        assert_eq!(merge_json("", r#"{"state":"ON"}"#).unwrap(), r#"{"state":"ON"}"#);
+        assert_eq!(merge_json("[1]", r#"{"state":"ON"}"#).unwrap(), r#"{"state":"ON"}"#);
+    }
+
+    #[test]
+    fn incoming_not_an_object() {
+        assert_eq!(merge_json(r#"{"state":"ON"}"#, "42").unwrap(), "42");
+        assert!(merge_json(r#"{"state":"ON"}"#, "not json").is_err());
+    }
+
+    #[test]
+    fn action_cleared_or_missing() {
+        assert_eq!(ActionEvent::from_json(r#"{"action":"on","battery":90}"#).unwrap().action, Some("on".to_string()));
+        assert_eq!(ActionEvent::from_json(r#"{"action":null}"#).unwrap().action, None);
+        assert_eq!(ActionEvent::from_json(r#"{"battery":90}"#).unwrap().action, None);
+    }
+}
@@ -3,9 +3,11 @@ use std::collections::HashMap;
 use std::sync::Arc;
 use log::info;
 use crate::dyn_device::DynDevice;
+use crate::error::AvaError;
 use crate::hall_lamp::{HALL_LAMP, HallLampDevice};
 use crate::kitchen_inter_dim::{KITCHEN_INTER_DIM, KitchenInterDimDevice};
 use crate::kitchen_lamp::{KITCHEN_LAMP, KitchenLampDevice};
+use crate::kitchen_remote::{KITCHEN_REMOTE, KitchenRemoteDevice};
 use crate::kitchen_switch::{KITCHEN_SWITCH, KitchenSwitchDevice};
 
 pub (crate) fn build_device_repo() -> HashMap<String, Arc<RefCell<dyn DynDevice>>> {
@@ -15,18 +17,25 @@ pub (crate) fn build_device_repo() -> HashMap<String, Arc<RefCell<dyn DynDevice>
     device_repo.insert(KITCHEN_INTER_DIM.to_owned(), Arc::new(RefCell::new(KitchenInterDimDevice::new())));
     device_repo.insert(KITCHEN_LAMP.to_owned(), Arc::new(RefCell::new(KitchenLampDevice::new())));
     device_repo.insert(HALL_LAMP.to_owned(), Arc::new(RefCell::new(HallLampDevice::new())));
+    device_repo.insert(KITCHEN_REMOTE.to_owned(), Arc::new(RefCell::new(KitchenRemoteDevice::new())));
     // device_repo.insert(TEMP_BAIE_VITREE.to_owned(), Arc::new(RefCell::new(InsideTempSensorDevice::new())));
     // device_repo.insert(TEMP_MEUBLE_TV.to_owned(), Arc::new(RefCell::new(OutdoorTempSensorDevice::new())));
     device_repo
 }
 
-pub (crate) fn device_to_listen(device_repo: &HashMap<String, Arc<RefCell<dyn DynDevice>>>) -> Vec<Arc<RefCell<dyn DynDevice>>> {
-    vec![
-        device_repo.get(KITCHEN_INTER_DIM).unwrap().clone(),
-        device_repo.get(KITCHEN_LAMP).unwrap().clone(),
-        device_repo.get(HALL_LAMP).unwrap().clone(),
-        // device_repo.get(TEMP_BAIE_VITREE).unwrap().clone(),
-        // device_repo.get(TEMP_MEUBLE_TV).unwrap().clone()
-        device_repo.get(KITCHEN_SWITCH).unwrap().clone(),
-    ]
+/// The device of the repository, a missing one is a configuration error
+pub (crate) fn find_device(device_repo: &HashMap<String, Arc<RefCell<dyn DynDevice>>>, name: &str) -> Result<Arc<RefCell<dyn DynDevice>>, AvaError> {
+    device_repo.get(name).cloned().ok_or_else(|| AvaError::Config(format!("unknown device [{}]", name)))
+}
+
+pub (crate) fn device_to_listen(device_repo: &HashMap<String, Arc<RefCell<dyn DynDevice>>>) -> Result<Vec<Arc<RefCell<dyn DynDevice>>>, AvaError> {
+    Ok(vec![
+        find_device(device_repo, KITCHEN_INTER_DIM)?,
+        find_device(device_repo, KITCHEN_LAMP)?,
+        find_device(device_repo, HALL_LAMP)?,
+        // find_device(device_repo, TEMP_BAIE_VITREE)?,
+        // find_device(device_repo, TEMP_MEUBLE_TV)?,
+        find_device(device_repo, KITCHEN_SWITCH)?,
+        find_device(device_repo, KITCHEN_REMOTE)?,
+    ])
 }
\ No newline at end of file
@@ -2,13 +2,16 @@ use std::cell::RefCell;
 use std::ops::Deref;
 use std::sync::Arc;
 
-use log::{error, info};
-use rumqttc::v5::AsyncClient;
-use rumqttc::v5::mqttbytes::QoS;
-use tokio::runtime;
+use log::info;
 
+use crate::availability::Availability;
 use crate::device_lock::DeviceLock;
-use crate::device_message::DeviceMessage;
+use crate::device_message::{DeviceMessage, merge_json};
+use crate::error::AvaError;
+use crate::origin::Origin;
+use crate::outbound::{OutboundCommand, OutboundQueue};
+use crate::policy::{CommandPolicy, DevicePolicy};
+use crate::twin::DesiredState;
 
 ///
 pub (crate) trait DynDevice {
@@ -21,9 +24,48 @@ pub (crate) trait DynDevice {
         todo!()
     }
 
+    fn get_policy(&self) -> DevicePolicy;
+    fn set_policy(&mut self, policy: DevicePolicy);
+
     fn get_topic(&self) -> String;
     fn is_init(&self) -> bool;
 
+    ///
+    /// Forget the init state and the locks, the device is going to be initialized again
+    ///
+    fn reset(&mut self) {
+        self.setup(false);
+        let lk = self.get_lock();
+        let mut dev_lock = lk.as_ref().borrow_mut();
+        dev_lock.release_locks();
+        dev_lock.desired = None;
+    }
+
+    fn is_available(&self) -> bool {
+        self.get_lock().as_ref().borrow().availability.is_available()
+    }
+
+    ///
+    /// Store the availability of the device, return true when it comes back online after being offline.
+    ///
+    fn update_availability(&self, availability: Availability) -> bool {
+        let lk = self.get_lock();
+        let mut dev_lock = lk.as_ref().borrow_mut();
+        let back_online = dev_lock.availability == Availability::Offline && availability == Availability::Online;
+        info!("📶 Device [{}] is {:?}", &self.get_topic().to_uppercase(), availability);
+        dev_lock.set_availability(availability);
+        back_online
+    }
+
+    ///
+    /// The full state of the device once the incoming (maybe partial) payload is merged into its last message
+    ///
+    fn merge_incoming(&self, msg: &str) -> Result<String, AvaError> {
+        let lk = self.get_lock();
+        let last = lk.as_ref().borrow().last_object_message.clone();
+        merge_json(&last, msg)
+    }
+
     fn init(&mut self, topic : &str, msg : &str) {
         let new_lock = {
             let lk = self.get_lock();
@@ -32,7 +74,9 @@ pub (crate) trait DynDevice {
             if topic == &self.get_topic() {
                 info!("✨ Init device [{}], with message <{}>",  &self.get_topic().to_uppercase(), &msg);
                 self.setup(true);
-                dev_lock.replace(msg.to_string());
+                let merged = merge_json(&dev_lock.last_object_message, msg).unwrap_or_else(|_| msg.to_string());
+                dev_lock.reported = merged.clone();
+                dev_lock.replace(merged);
                 info!("Init done");
             }
             dev_lock
@@ -43,18 +87,32 @@ pub (crate) trait DynDevice {
     /// Send the message on the right end point (/get) to trigger the device properties on the bus
     fn trigger_info(&self) -> Vec<u8>;
 
-    fn from_json_to_local(&self, msg: &str) -> Result<Box<dyn DeviceMessage>, String>;
+    fn from_json_to_local(&self, msg: &str) -> Result<Box<dyn DeviceMessage>, AvaError>;
 
 
-    fn allowed_to_process(&self, object_message : &Box<dyn DeviceMessage>) -> (bool, bool) {
+    ///
+    /// Tell if the message is the state asked by one of the commands in flight (an echo), and if it's the same as the last state.
+    ///
+    fn allowed_to_process(&self, object_message : &Box<dyn DeviceMessage>) -> Result<(bool, bool), AvaError> {
         let lk = self.get_lock();
         let borr = lk.as_ref().borrow();
         let dev_lock = borr.deref().clone();
 
-        let incoming_message = object_message.to_json().unwrap();
-        let is_locked = dev_lock.count_locks > 0;
-        let is_same = *incoming_message == dev_lock.last_object_message;
-        (is_locked, is_same)
+        let incoming_message = object_message.to_json()?;
+        let comparison = self.get_policy().comparison;
+        let is_echo = dev_lock.in_flight.iter().any(|message| comparison.same_state(&incoming_message, message));
+        let is_same = comparison.same_state(&incoming_message, &dev_lock.last_object_message);
+        Ok((is_echo, is_same))
+    }
+
+    ///
+    /// The last message of the device, in its own format
+    ///
+    fn last_message(&self) -> Result<Box<dyn DeviceMessage>, AvaError> {
+        let lk = self.get_lock();
+        let last = lk.as_ref().borrow().last_object_message.clone();
+        self.from_json_to_local(&last)
+            .map_err(|e| AvaError::State(format!("cannot read the last message of device {}, message=<{}>, {}", &self.get_topic().to_uppercase(), &last, e)))
     }
 
     ///
@@ -68,7 +126,7 @@ pub (crate) trait DynDevice {
     ///
     /// Run the local specific processing if allowed.
     ///
-    fn process_and_continue(&self, original_message : &Box<dyn DeviceMessage>) -> bool {
+    fn process_and_continue(&self, original_message : &Box<dyn DeviceMessage>) -> Result<bool, AvaError> {
 
         info!("process_and_continue");
         let (new_lock, allowed) = {
@@ -76,11 +134,13 @@ pub (crate) trait DynDevice {
             let borr = lk.as_ref().borrow();
             let mut dev_lock = borr.deref().clone();
             let allowed: bool;
-            match self.allowed_to_process(&original_message) {
+            let (is_echo, is_same) = self.allowed_to_process(&original_message)?;
+            let json_message = original_message.to_json()?;
+            match (is_echo, is_same) {
                 (true, _) => {
-                    info!("❌ Device {} is locked.", & self.get_topic().to_uppercase());
-                    // self.unlock(&mut locks);
-                    dev_lock.dec();
+                    info!("❌ Device {} is locked, echo of a command.", & self.get_topic().to_uppercase());
+                    let comparison = self.get_policy().comparison;
+                    dev_lock.take_echo(|message| comparison.same_state(&json_message, message));
                     allowed = false;
                 }
                 (false, true) => {
@@ -88,88 +148,135 @@ pub (crate) trait DynDevice {
                     allowed = false;
                 }
                 (false, false) => {
+                    // Even with commands in flight, a state none of them asked for is a change made on the device
                     info!("👍 Device {}, allowed to process the message.", & self.get_topic().to_uppercase());
                     self.process(&original_message);
                     allowed = true;
                 }
             }
-            let json_message = original_message.to_json().unwrap().clone();
-            dev_lock.replace(json_message);
+            dev_lock.reported = json_message.clone();
+            // Within the tolerance, the state stays the one of reference : small moves add up until they make a change
+            if !is_same {
+                dev_lock.replace(json_message);
+            }
             (dev_lock, allowed)
         };
         self.get_lock().replace(new_lock);
-        allowed
+        Ok(allowed)
     }
 
     ///
     /// Make the device consume the current message
     ///
-    fn consume_message(&self, original_message : &Box<dyn DeviceMessage>, mut client: &mut AsyncClient) {
+    fn consume_message(&self, original_message : &Box<dyn DeviceMessage>, outbound: &mut OutboundQueue, origin: &Origin, policy: &CommandPolicy) -> Result<(), AvaError> {
         info!("The device is consuming the message");
         let new_lock = {
+            // Convert the incoming message to the format the device needs. Last message est du même format que le message du device. Il permet de récupérer certaines informations.
+            // Ex : Incoming inter dim message + last (LampRGB) ---> hall_lamp message (LampRGB)
+            let last_message = self.last_message()?;
+
             let lk = self.get_lock();
             let borr = lk.as_ref().borrow();
             let mut dev_lock = borr.deref().clone();
 
             info!("Execute device {}", & self.get_topic().to_uppercase());
 
-            // Convert the incoming message to the format the device needs. Last message est du même format que le message du device. Il permet de récupérer certaines informations.
-            // Ex : Incoming inter dim message + last (LampRGB) ---> hall_lamp message (LampRGB)
-            let last_message = match self.from_json_to_local(&dev_lock.last_object_message)  {
-                Err(e) => {
-                    error!("💀 Cannot parse the message for device {}, message=<{}>, \n e={}", &self.get_topic().to_uppercase(), &dev_lock.last_object_message, e);
-                    return;
-                }
-                Ok(lm) => lm
-            };
             let object_message = self.to_local(&original_message, &last_message);
+            let json_message = object_message.to_json()
+                .map_err(|e| AvaError::Conversion(format!("cannot build the message of device {}, {}", &self.get_topic().to_uppercase(), e)))?;
 
-            match self.allowed_to_process(&object_message) {
-                (true, _) => {
-                    info!("⛔ Device {} is locked.", & self.get_topic().to_uppercase());
-                    info!("Incoming message : {:?}, last message : {:?}", &object_message.to_json(), &dev_lock.last_object_message);
-                    dev_lock.dec();
-                    // self.unlock(&mut locks);
-                }
-                (false, true) => {
+            // The commands in flight don't prevent another one, the last state is the one of the latest
+            match self.allowed_to_process(&object_message)? {
+                (_, true) => {
+                    // The last state stays the reference of the tolerance
                     info!("⛔ Device {}, same message.", & self.get_topic().to_uppercase());
                     info!("Incoming message : {:?}, last message : {:?}", &object_message.to_json(), &dev_lock.last_object_message);
                 }
-                (false, false) => {
+                (_, false) => {
                     info!("🍺 Device {}, process the message.", & self.get_topic().to_uppercase());
                     info!("Incoming message : {:?}, last message : {:?}", &object_message.to_json(), &dev_lock.last_object_message);
-                    dev_lock.inc();
-                    self.publish_message(&mut client, &object_message);
+                    let replaced = self.publish_message(outbound, &json_message, origin, policy);
+                    dev_lock.expect_echo(&json_message, replaced);
+                    dev_lock.desired = Some(DesiredState::new(&json_message, *policy, origin, &self.get_policy().reconcile));
+                    dev_lock.replace(json_message);
                 }
             }
-            let json_message = object_message.to_json().unwrap().clone();
-            dev_lock.replace(json_message);
 
             let message_locked = &dev_lock.last_object_message;
             info!("Now last : {:?}", &message_locked);
             dev_lock
         };
         self.get_lock().replace(new_lock);
+        Ok(())
     }
 
-    fn publish_message(&self, mut client: &mut AsyncClient, object_message : &Box<dyn DeviceMessage>) {
-        match object_message.to_json() {
-            Ok(message) => {
-                info!("➡ Prepare to be sent to the {}, {:?} ", &self.get_topic().to_uppercase(), &message);
-                // Créer un runtime asynchrone avec tokio
-                let rt = runtime::Runtime::new().unwrap();
-                // Appeler la fonction asynchrone à l'intérieur du runtime
-                rt.block_on(async {
-                    let data = message.as_bytes().to_vec();
-                    client.publish(&format!("{}/set", &self.get_topic()), QoS::AtLeastOnce, false, data).await.unwrap(); // TODO unwrap handle
-                });
-            }
-            Err(e) => {
-                error!("💣 Impossible to parse the message : e={:?}", e);
-            }
+    ///
+    /// The command the device would get from the message, none when it has nothing to do (same state).
+    /// Nothing is changed, see track_command.
+    ///
+    fn command_for(&self, original_message : &Box<dyn DeviceMessage>) -> Result<Option<String>, AvaError> {
+        let last_message = self.last_message()?;
+        let object_message = self.to_local(&original_message, &last_message);
+        let json_message = object_message.to_json()
+            .map_err(|e| AvaError::Conversion(format!("cannot build the message of device {}, {}", &self.get_topic().to_uppercase(), e)))?;
+        match self.allowed_to_process(&object_message)? {
+            (_, false) => Ok(Some(json_message)),
+            (_, true) => Ok(None),
         }
     }
 
+    ///
+    /// Record a command sent to the device by another way (ex : its zigbee2mqtt group), like consume_message does
+    ///
+    fn track_command(&self, json_message : &str, origin: &Origin, policy: &CommandPolicy) {
+        let new_lock = {
+            let lk = self.get_lock();
+            let borr = lk.as_ref().borrow();
+            let mut dev_lock = borr.deref().clone();
+            dev_lock.expect_echo(json_message, false);
+            dev_lock.desired = Some(DesiredState::new(json_message, *policy, origin, &self.get_policy().reconcile));
+            dev_lock.replace(json_message.to_string());
+            dev_lock
+        };
+        self.get_lock().replace(new_lock);
+    }
+
+    ///
+    /// Push the converted message to the device, even if it's the same as its last one.
+    /// Used to re-sync a device that was offline with the state of its loop, and for the direct commands of a remote.
+    ///
+    fn resync(&self, original_message : &Box<dyn DeviceMessage>, outbound: &mut OutboundQueue, origin: &Origin, policy: &CommandPolicy) -> Result<(), AvaError> {
+        let new_lock = {
+            let last_message = self.last_message()?;
+
+            let lk = self.get_lock();
+            let borr = lk.as_ref().borrow();
+            let mut dev_lock = borr.deref().clone();
+
+            let object_message = self.to_local(&original_message, &last_message);
+            let json_message = object_message.to_json()
+                .map_err(|e| AvaError::Conversion(format!("cannot build the message of device {}, {}", &self.get_topic().to_uppercase(), e)))?;
+
+            info!("🔄 Re-sync device {}, message : {:?}", & self.get_topic().to_uppercase(), &json_message);
+            let replaced = self.publish_message(outbound, &json_message, origin, policy);
+            dev_lock.expect_echo(&json_message, replaced);
+            dev_lock.desired = Some(DesiredState::new(&json_message, *policy, origin, &self.get_policy().reconcile));
+            dev_lock.replace(json_message);
+            dev_lock
+        };
+        self.get_lock().replace(new_lock);
+        Ok(())
+    }
+
+    ///
+    /// The command waits in the outbound queue until the broker is reachable.
+    /// Return true when it replaced a command not sent yet, no new echo is expected then.
+    ///
+    fn publish_message(&self, outbound: &mut OutboundQueue, message : &str, origin: &Origin, policy: &CommandPolicy) -> bool {
+        info!("➡ Prepare to be sent to the {}, {:?}, cause={} ", &self.get_topic().to_uppercase(), message, &origin.causation_id);
+        outbound.push_latest(OutboundCommand::new(&format!("{}/set", &self.get_topic()), message, policy, origin).with_locks(vec![self.get_topic()]))
+    }
+
     // Could be a method of a receiver trait
     // fn receive(&self, mut pub_stream: &mut TcpStream, object_message : Box<dyn DeviceMessage>) {
     //     match object_message.to_json() {
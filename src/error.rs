@@ -0,0 +1,79 @@
+use std::fmt;
+use std::str::Utf8Error;
+
+use log::error;
+use rumqttc::v5::ClientError;
+use serde_derive::*;
+
+///
+/// Everything that can go wrong in AVA.
+/// A failure on one message is logged and counted, it never stops the daemon.
+///
+#[derive(Debug, Clone)]
+pub (crate) enum AvaError {
+    // The broker or the mqtt client
+    Transport(String),
+    // A payload that is not the expected json (or not even utf8)
+    Parse(String),
+    // A message that cannot be converted for another device
+    Conversion(String),
+    // Wrong settings, only at startup
+    Config(String),
+    // The state of a device (last message, saved state)
+    State(String),
+}
+
+impl fmt::Display for AvaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AvaError::Transport(e) => write!(f, "transport error, {}", e),
+            AvaError::Parse(e) => write!(f, "parse error, {}", e),
+            AvaError::Conversion(e) => write!(f, "conversion error, {}", e),
+            AvaError::Config(e) => write!(f, "configuration error, {}", e),
+            AvaError::State(e) => write!(f, "state error, {}", e),
+        }
+    }
+}
+
+impl From<serde_json::Error> for AvaError {
+    fn from(e: serde_json::Error) -> Self {
+        AvaError::Parse(e.to_string())
+    }
+}
+
+impl From<Utf8Error> for AvaError {
+    fn from(e: Utf8Error) -> Self {
+        AvaError::Parse(format!("invalid utf8, {}", e))
+    }
+}
+
+impl From<ClientError> for AvaError {
+    fn from(e: ClientError) -> Self {
+        AvaError::Transport(format!("{:?}", e))
+    }
+}
+
+///
+/// Number of failures by kind since the start, published in the heartbeat
+///
+#[derive(Serialize, Debug, Default, Clone)]
+pub (crate) struct ErrorCounters {
+    pub transport: u64,
+    pub parse: u64,
+    pub conversion: u64,
+    pub config: u64,
+    pub state: u64,
+}
+
+impl ErrorCounters {
+    pub (crate) fn record(&mut self, e: &AvaError) {
+        error!("💣 {}", e);
+        match e {
+            AvaError::Transport(_) => self.transport += 1,
+            AvaError::Parse(_) => self.parse += 1,
+            AvaError::Conversion(_) => self.conversion += 1,
+            AvaError::Config(_) => self.config += 1,
+            AvaError::State(_) => self.state += 1,
+        }
+    }
+}
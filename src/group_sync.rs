@@ -0,0 +1,214 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use log::{info, warn};
+use serde_derive::*;
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use crate::ava_status::AVA_EVENT_TOPIC;
+use crate::error::AvaError;
+use crate::groups::{GroupMembers, GroupRegistry};
+use crate::loops::HardLoop;
+use crate::origin::Origin;
+use crate::outbound::{now_millis, OutboundCommand, OutboundQueue};
+use crate::policy::CommandPolicy;
+
+const GROUP_REQUEST_PREFIX: &str = "zigbee2mqtt/bridge/request/group";
+pub (crate) const GROUP_RESPONSE_FILTER: &str = "zigbee2mqtt/bridge/response/group/#";
+
+const GROUP_ADD: &str = "add";
+const MEMBER_ADD: &str = "members/add";
+const MEMBER_REMOVE: &str = "members/remove";
+// A request without response after that is considered lost, the next reconciliation sends it again
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+///
+/// A request sent to the bridge, kept until its response
+///
+#[derive(Debug, Clone, PartialEq)]
+struct GroupRequest {
+    action: &'static str,
+    group: String,
+    device: Option<String>,
+}
+
+///
+/// Reply of zigbee2mqtt on bridge/response/group/...
+///
+#[derive(Deserialize, Debug)]
+struct BridgeResponse {
+    status: String,
+    #[serde(default)]
+    error: Option<String>,
+    #[serde(default)]
+    transaction: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct GroupEvent {
+    event: String,
+    group: String,
+    missing: Vec<String>,
+    extra: Vec<String>,
+    unknown: Vec<String>,
+}
+
+///
+/// Keep the zigbee2mqtt groups in line with the configured groups of the loops : the loops are the reference.
+/// The groups read from the bridge are left alone.
+///
+#[derive(Debug, Default)]
+pub (crate) struct GroupSync {
+    // Requests waiting for their response with their epoch millis, by transaction
+    pending: HashMap<String, (GroupRequest, u64)>,
+}
+
+impl GroupSync {
+
+    ///
+    /// Compare the configured groups with the bridge ones, request the missing groups and members, remove the extra members.
+    /// The discrepancies are reported on the event topic.
+    ///
+    pub (crate) fn sync(&mut self, all_loops: &[HardLoop], registry: &GroupRegistry, outbound: &mut OutboundQueue, origin: &Origin) {
+        if !registry.is_ready() {
+            info!("👪 Groups of the bridge not known yet, no reconciliation");
+            return;
+        }
+        let now = now_millis();
+        self.expire(now);
+        for group in all_loops.iter().filter_map(|lp| lp.group) {
+            let configured = match group.members {
+                GroupMembers::Configured(names) => names,
+                GroupMembers::FromBridge => continue,
+            };
+            let bridge_members = registry.bridge_members(group.name);
+            if bridge_members.is_none() {
+                self.request(GroupRequest { action: GROUP_ADD, group: group.name.to_string(), device: None }, now, outbound, origin);
+            }
+            let bridge_members = bridge_members.unwrap_or_default();
+
+            let unknown: Vec<String> = configured.iter().filter(|n| !registry.knows_device(n)).map(|n| n.to_string()).collect();
+            let missing: Vec<String> = configured.iter()
+                .filter(|n| !bridge_members.iter().any(|m| m == *n) && registry.knows_device(n))
+                .map(|n| n.to_string())
+                .collect();
+            let extra: Vec<String> = bridge_members.iter().filter(|m| !configured.contains(&m.as_str())).cloned().collect();
+            if missing.is_empty() && extra.is_empty() && unknown.is_empty() {
+                info!("👪 Group [{}] is in line with its loop", group.name);
+                continue;
+            }
+
+            warn!("👪 Group [{}] differs from its loop, missing {:?}, extra {:?}, unknown {:?}", group.name, &missing, &extra, &unknown);
+            for device in &missing {
+                self.request(GroupRequest { action: MEMBER_ADD, group: group.name.to_string(), device: Some(device.clone()) }, now, outbound, origin);
+            }
+            for device in &extra {
+                self.request(GroupRequest { action: MEMBER_REMOVE, group: group.name.to_string(), device: Some(device.clone()) }, now, outbound, origin);
+            }
+            let event = GroupEvent {
+                event: "group".to_string(),
+                group: group.name.to_string(),
+                missing,
+                extra,
+                unknown,
+            };
+            outbound.push_json(AVA_EVENT_TOPIC, &event, &CommandPolicy::default(), origin);
+        }
+    }
+
+    /// Forget the requests whose response never came
+    fn expire(&mut self, now: u64) {
+        let timeout = REQUEST_TIMEOUT.as_millis() as u64;
+        self.pending.retain(|_, (request, sent_at)| {
+            let alive = now < *sent_at + timeout;
+            if !alive {
+                warn!("👪 No response to the request [{}] on group [{}], device {:?}", request.action, &request.group, &request.device);
+            }
+            alive
+        });
+    }
+
+    /// The bridge has restarted, the requests in flight won't get a response
+    pub (crate) fn forget_pending(&mut self) {
+        self.pending.clear();
+    }
+
+    /// Send the request, unless the same one is still waiting for its response
+    fn request(&mut self, request: GroupRequest, now: u64, outbound: &mut OutboundQueue, origin: &Origin) {
+        if self.pending.values().any(|(r, _)| r == &request) {
+            return;
+        }
+        let transaction = Uuid::new_v4().to_string();
+        let payload: Value = match &request.device {
+            None => json!({"friendly_name": &request.group, "transaction": &transaction}),
+            Some(device) => json!({"group": &request.group, "device": device, "transaction": &transaction}),
+        };
+        info!("👪 Request [{}] on group [{}], device {:?}", request.action, &request.group, &request.device);
+        outbound.push(OutboundCommand::new(&format!("{}/{}", GROUP_REQUEST_PREFIX, request.action), &payload.to_string(), &CommandPolicy::default(), origin));
+        self.pending.insert(transaction, (request, now));
+    }
+
+    ///
+    /// Read the reply of the bridge to one of our requests
+    ///
+    pub (crate) fn on_response(&mut self, topic: &str, msg: &str) -> Result<(), AvaError> {
+        let response: BridgeResponse = serde_json::from_str(msg)?;
+        let request = response.transaction.as_ref().and_then(|t| self.pending.remove(t)).map(|(request, _)| request);
+        match (response.status.as_str(), request) {
+            // Not ours (ex : a request sent from the zigbee2mqtt frontend)
+            (_, None) => Ok(()),
+            ("ok", Some(request)) => {
+                info!("👪 Request [{}] on group [{}] done, device {:?}", request.action, &request.group, &request.device);
+                Ok(())
+            }
+            (_, Some(request)) => {
+                Err(AvaError::Transport(format!("request [{}] on group [{}] failed on [{}], device {:?}, {}",
+                                                request.action, &request.group, topic, &request.device, response.error.unwrap_or_default())))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn add(device: &str) -> GroupRequest {
+        GroupRequest { action: MEMBER_ADD, group: "lamps".to_string(), device: Some(device.to_string()) }
+    }
+
+    #[test]
+    fn same_request_sent_once_until_it_expires() {
+        let mut sync = GroupSync::default();
+        let mut outbound = OutboundQueue::new(10, None);
+        let origin = Origin::new_cause("test");
+        sync.request(add("hall_lamp"), 1_000, &mut outbound, &origin);
+        sync.request(add("hall_lamp"), 2_000, &mut outbound, &origin);
+        sync.request(add("kitchen_lamp"), 2_000, &mut outbound, &origin);
+        assert_eq!(outbound.len(), 2);
+
+        sync.expire(1_000 + REQUEST_TIMEOUT.as_millis() as u64);
+        assert_eq!(sync.pending.len(), 1);
+        sync.request(add("hall_lamp"), 40_000, &mut outbound, &origin);
+        assert_eq!(outbound.len(), 3);
+
+        sync.forget_pending();
+        assert!(sync.pending.is_empty());
+    }
+
+    #[test]
+    fn response_ends_the_request() {
+        let mut sync = GroupSync::default();
+        let mut outbound = OutboundQueue::new(10, None);
+        sync.request(add("hall_lamp"), 1_000, &mut outbound, &Origin::new_cause("test"));
+        let transaction = sync.pending.keys().next().unwrap().clone();
+
+        let topic = "zigbee2mqtt/bridge/response/group/members/add";
+        assert!(sync.on_response(topic, r#"{"status":"ok","transaction":"other"}"#).is_ok());
+        assert_eq!(sync.pending.len(), 1);
+        let failed = format!(r#"{{"status":"error","error":"unknown device","transaction":"{}"}}"#, transaction);
+        assert!(sync.on_response(topic, &failed).is_err());
+        assert!(sync.pending.is_empty());
+    }
+}
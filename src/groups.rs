@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+
+use log::info;
+use serde_derive::*;
+
+use crate::error::AvaError;
+
+pub (crate) const BRIDGE_GROUPS_TOPIC: &str = "zigbee2mqtt/bridge/groups";
+pub (crate) const BRIDGE_DEVICES_TOPIC: &str = "zigbee2mqtt/bridge/devices";
+
+///
+/// Where the members of a zigbee2mqtt group come from
+///
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[allow(dead_code)]
+pub (crate) enum GroupMembers {
+    // Names of the devices, as in the device repository
+    Configured(&'static [&'static str]),
+    // Read from zigbee2mqtt/bridge/groups
+    FromBridge,
+}
+
+///
+/// The zigbee2mqtt group a loop commands at once, when all its members get the same state
+///
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub (crate) struct LoopGroup {
+    pub name: &'static str,
+    pub members: GroupMembers,
+}
+
+#[derive(Deserialize, Debug)]
+struct BridgeDevice {
+    ieee_address: String,
+    friendly_name: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct BridgeGroupMember {
+    ieee_address: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct BridgeGroup {
+    friendly_name: String,
+    #[serde(default)]
+    members: Vec<BridgeGroupMember>,
+}
+
+///
+/// The groups and the devices published by zigbee2mqtt (retained).
+/// The groups list their members by ieee address, the devices give their names.
+///
+#[derive(Debug, Clone, Default)]
+pub (crate) struct GroupRegistry {
+    // Friendly name by ieee address
+    devices: HashMap<String, String>,
+    // Ieee addresses of the members, by group name, none until the bridge published them
+    groups: Option<HashMap<String, Vec<String>>>,
+}
+
+impl GroupRegistry {
+
+    pub (crate) fn update_devices(&mut self, msg: &str) -> Result<(), AvaError> {
+        let devices: Vec<BridgeDevice> = serde_json::from_str(msg)?;
+        info!("🌉 [{}] devices known by the bridge", devices.len());
+        self.devices = devices.into_iter().map(|d| (d.ieee_address, d.friendly_name)).collect();
+        Ok(())
+    }
+
+    pub (crate) fn update_groups(&mut self, msg: &str) -> Result<(), AvaError> {
+        let groups: Vec<BridgeGroup> = serde_json::from_str(msg)?;
+        info!("🌉 [{}] groups known by the bridge", groups.len());
+        self.groups = Some(groups.into_iter()
+            .map(|g| (g.friendly_name, g.members.into_iter().map(|m| m.ieee_address).collect()))
+            .collect());
+        Ok(())
+    }
+
+    /// Both the devices and the groups of the bridge are known
+    pub (crate) fn is_ready(&self) -> bool {
+        !self.devices.is_empty() && self.groups.is_some()
+    }
+
+    pub (crate) fn knows_device(&self, name: &str) -> bool {
+        self.devices.values().any(|n| n == name)
+    }
+
+    ///
+    /// The members of the group as the bridge sees them, by name (by ieee address for the devices it doesn't name).
+    /// None when the group doesn't exist.
+    ///
+    pub (crate) fn bridge_members(&self, group: &str) -> Option<Vec<String>> {
+        let mut names: Vec<String> = self.groups.as_ref()?.get(group)?.iter()
+            .map(|ieee| self.devices.get(ieee).cloned().unwrap_or_else(|| ieee.clone()))
+            .collect();
+        // A device with several endpoints in the group
+        names.sort();
+        names.dedup();
+        Some(names)
+    }
+
+    ///
+    /// The names of the members of the group, none while the bridge has not told them.
+    /// A configured group is only used once the bridge has it with the same members, until then it's reconciled.
+    ///
+    pub (crate) fn members(&self, group: &LoopGroup) -> Option<Vec<String>> {
+        if !self.is_ready() {
+            return None;
+        }
+        match group.members {
+            GroupMembers::Configured(names) => {
+                let mut configured: Vec<String> = names.iter().map(|n| n.to_string()).collect();
+                configured.sort();
+                if self.bridge_members(group.name).as_ref() != Some(&configured) {
+                    return None;
+                }
+                Some(configured)
+            }
+            GroupMembers::FromBridge => {
+                let names = self.bridge_members(group.name)?;
+                if names.iter().all(|n| self.knows_device(n)) {
+                    Some(names)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEVICES: &str = r#"[{"ieee_address":"0x01","friendly_name":"hall_lamp"},{"ieee_address":"0x02","friendly_name":"kitchen_lamp"}]"#;
+    const GROUP: LoopGroup = LoopGroup { name: "lamps", members: GroupMembers::Configured(&["kitchen_lamp", "hall_lamp"]) };
+
+    #[test]
+    fn configured_group_waits_for_the_bridge() {
+        let mut registry = GroupRegistry::default();
+        assert_eq!(registry.members(&GROUP), None);
+        registry.update_devices(DEVICES).unwrap();
+        assert_eq!(registry.members(&GROUP), None);
+        registry.update_groups(r#"[]"#).unwrap();
+        assert_eq!(registry.members(&GROUP), None);
+        registry.update_groups(r#"[{"friendly_name":"lamps","members":[{"ieee_address":"0x01"}]}]"#).unwrap();
+        assert_eq!(registry.members(&GROUP), None);
+        registry.update_groups(r#"[{"friendly_name":"lamps","members":[{"ieee_address":"0x01"},{"ieee_address":"0x02"}]}]"#).unwrap();
+        assert_eq!(registry.members(&GROUP), Some(vec!["hall_lamp".to_string(), "kitchen_lamp".to_string()]));
+    }
+}
@@ -6,6 +6,8 @@ use log::info;
 use crate::device_lock::DeviceLock;
 use crate::device_message::{DeviceMessage, LampRGB};
 use crate::dyn_device::DynDevice;
+use crate::error::AvaError;
+use crate::policy::DevicePolicy;
 
 pub(crate) const HALL_LAMP : &str = "hall_lamp";
 
@@ -13,6 +15,7 @@ pub(crate) const HALL_LAMP : &str = "hall_lamp";
 pub(crate) struct HallLampDevice {
     pub lock : Arc<RefCell<DeviceLock<String>>>,
     pub setup : bool,
+    pub policy : DevicePolicy,
 }
 
 // TODO generalise the struct to handle all the "Lamp" family, pass the name in the constructor.
@@ -23,6 +26,7 @@ impl HallLampDevice {
         Self {
             lock : Arc::new(RefCell::new( dl )),
             setup: false,
+            policy: DevicePolicy::default(),
         }
     }
     pub fn get_name() -> &'static str {
@@ -36,6 +40,14 @@ impl DynDevice for HallLampDevice {
         self.lock.clone()
     }
 
+    fn get_policy(&self) -> DevicePolicy {
+        self.policy
+    }
+
+    fn set_policy(&mut self, policy: DevicePolicy) {
+        self.policy = policy;
+    }
+
     fn setup(&mut self, setup: bool) {
         self.setup = setup;
     }
@@ -48,7 +60,7 @@ impl DynDevice for HallLampDevice {
         self.setup
     }
 
-    fn from_json_to_local(&self, msg: &str) -> Result<Box<dyn DeviceMessage>, String> {
+    fn from_json_to_local(&self, msg: &str) -> Result<Box<dyn DeviceMessage>, AvaError> {
         Ok(Box::new( LampRGB::from_json(msg)? ))
     }
 
@@ -3,48 +3,65 @@ use std::collections::HashMap;
 use std::ops::{Deref, DerefMut};
 use std::sync::Arc;
 
-use log::info;
+use log::{error, info};
 use rumqttc::v5::{AsyncClient, Event, Incoming};
 use rumqttc::v5::EventLoop;
 use rumqttc::v5::mqttbytes::QoS;
 
+use crate::availability::{Availability, device_topic_of};
+use crate::bridge::{Bridge, BRIDGE_STATE_TOPIC};
+use crate::device_repo::find_device;
 use crate::dyn_device::DynDevice;
+use crate::error::AvaError;
+use crate::groups::{BRIDGE_DEVICES_TOPIC, BRIDGE_GROUPS_TOPIC};
 use crate::hall_lamp::HALL_LAMP;
 use crate::kitchen_lamp::KITCHEN_LAMP;
 
-pub (crate) fn build_init_list(device_repo : &HashMap<String, Arc<RefCell<dyn DynDevice>>>) -> Vec<Arc<RefCell<dyn DynDevice>>> {
-    vec![
-        device_repo.get(KITCHEN_LAMP).unwrap().clone(),
-        device_repo.get(HALL_LAMP).unwrap().clone()
-    ]
+pub (crate) fn build_init_list(device_repo : &HashMap<String, Arc<RefCell<dyn DynDevice>>>) -> Result<Vec<Arc<RefCell<dyn DynDevice>>>, AvaError> {
+    Ok(vec![
+        find_device(device_repo, KITCHEN_LAMP)?,
+        find_device(device_repo, HALL_LAMP)?,
+    ])
 }
 
 ///
+/// Wait for the zigbee2mqtt bridge to be online
 /// Send an information message for all the device we want to init
 /// Read the responses from mosquitto and run the init routine for the devices.
 ///
-pub (crate) async fn process_initialization_message(mut client: &mut AsyncClient, mut eventloop: &mut EventLoop, device_to_init: &Vec<Arc<RefCell<dyn DynDevice>>>) -> Result<(), String> {
+pub (crate) async fn process_initialization_message(mut client: &mut AsyncClient, mut eventloop: &mut EventLoop, device_to_init: &Vec<Arc<RefCell<dyn DynDevice>>>, bridge: &mut Bridge) -> Result<(), AvaError> {
 
     info!("Initialisation stage starts");
 
     if !device_to_init.is_empty() {
+        if !bridge.is_online() {
+            info!("⏳ Wait for the zigbee2mqtt bridge to be online");
+            while !bridge.is_online() {
+                let notification = eventloop.poll().await
+                    .map_err(|e| AvaError::Transport(format!("connection lost while waiting for the zigbee2mqtt bridge, {}", e)))?;
+                handle_event(notification, device_to_init, bridge).await;
+            }
+        }
+
         for dev in device_to_init {
             let borr = dev.as_ref().borrow();
             let dd = borr.deref().clone();
 
-            dbg!("Topic", &dd.get_topic());
             let data = dd.trigger_info();
-            client.publish(&format!("{}/get", &dd.get_topic()), QoS::AtLeastOnce, false,  data).await.unwrap(); // TODO
+            client.publish(&format!("{}/get", &dd.get_topic()), QoS::AtLeastOnce, false,  data).await?;
         }
 
-        while let Ok(notification) = eventloop.poll().await {
+        loop {
+            let notification = eventloop.poll().await
+                .map_err(|e| AvaError::Transport(format!("connection lost while initializing the devices, {}", e)))?;
             let mut end_loop = true;
-            handle_event(notification, device_to_init).await;
+            handle_event(notification, device_to_init, bridge).await;
             for dev in device_to_init {
                 info!("Devices before check ----------");
                 let borr = dev.as_ref().borrow();
                 let dd = borr.deref().clone();
-                if !dd.is_init() {
+                // An offline device won't answer, it will be re-synced when it comes back
+                if !dd.is_init() && dd.is_available() {
                     end_loop = false;
                 }
             }
@@ -59,25 +76,46 @@ pub (crate) async fn process_initialization_message(mut client: &mut AsyncClient
     Ok(())
 }
 
-async fn handle_event(event: Event, device_to_init: &Vec<Arc<RefCell<dyn DynDevice>>>) {
-    println!("Message reçu = {:?}", &event);
+async fn handle_event(event: Event, device_to_init: &Vec<Arc<RefCell<dyn DynDevice>>>, bridge: &mut Bridge) {
     match event {
         Event::Incoming(Incoming::Publish(publish)) => {
             // Votre logique de traitement des messages ici
 
-            let msg = std::str::from_utf8(&publish.payload).unwrap();
-            let topic = std::str::from_utf8(publish.topic.as_ref()).unwrap(); // TODO
+            let (topic, msg) = match (std::str::from_utf8(publish.topic.as_ref()), std::str::from_utf8(&publish.payload)) {
+                (Ok(topic), Ok(msg)) => (topic, msg),
+                (Err(e), _) | (_, Err(e)) => {
+                    error!("💣 {}", AvaError::from(e));
+                    return;
+                }
+            };
+
+            info!("PUBLISH ({}): {}", topic, msg);
 
-            if let Some(properties) = publish.properties {
-                // Vous pouvez accéder à différentes propriétés ici
-                println!(">>>> Propriétés de la réponse de publish: {:?}", properties);
-            } else {
-                println!("No properties on publish")
+            if topic == BRIDGE_STATE_TOPIC {
+                bridge.update(msg);
+                return;
             }
 
-            println!( "Message reçu sur le topic {:?}: {:?}",topic  , msg    );
+            // Retained, they come with the subscription
+            if topic == BRIDGE_DEVICES_TOPIC || topic == BRIDGE_GROUPS_TOPIC {
+                let updated = if topic == BRIDGE_DEVICES_TOPIC { bridge.groups.update_devices(msg) } else { bridge.groups.update_groups(msg) };
+                if let Err(e) = updated {
+                    error!("💣 Cannot read [{}], {}", topic, e);
+                }
+                return;
+            }
 
-            info!("PUBLISH ({}): {}", topic, msg);
+            if let Some(device_topic) = device_topic_of(topic) {
+                if let Some(availability) = Availability::from_payload(msg) {
+                    for dev in device_to_init {
+                        let borr = dev.as_ref().borrow();
+                        if borr.get_topic() == device_topic {
+                            borr.update_availability(availability);
+                        }
+                    }
+                }
+                return;
+            }
 
             // TODO is it necessary to loop over all the devices ?
             for dev in device_to_init {
@@ -87,22 +125,6 @@ async fn handle_event(event: Event, device_to_init: &Vec<Arc<RefCell<dyn DynDevi
             }
 
         }
-        Event::Incoming(Incoming::ConnAck(connack)) => {
-            // Accéder aux métadonnées de la réponse de connexion (Connack)
-            if let Some(properties) = connack.properties {
-                // Vous pouvez accéder à différentes propriétés ici
-                println!("Propriétés de la réponse de connexion: {:?}", properties);
-            }
-        }
-        Event::Incoming(Incoming::PubAck(pubAck)) => {
-            // Accéder aux métadonnées de la réponse de connexion (Connack)
-            if let Some(properties) = pubAck.properties {
-                // Vous pouvez accéder à différentes propriétés ici
-                println!("Propriétés de la réponse de connexion: {:?}", properties);
-            } else {
-                println!("No properties on puback")
-            }
-        }
         _ => {}
     }
 }
\ No newline at end of file
@@ -6,12 +6,15 @@ use log::info;
 use crate::device_lock::DeviceLock;
 use crate::device_message::{DeviceMessage, InterDim};
 use crate::dyn_device::DynDevice;
+use crate::error::AvaError;
+use crate::policy::DevicePolicy;
 
 pub (crate) const KITCHEN_INTER_DIM : &str = "kitchen_inter_dim";
 
 #[derive(Debug)]
 pub (crate) struct KitchenInterDimDevice {
-    pub lock : Arc<RefCell<DeviceLock<String>>>
+    pub lock : Arc<RefCell<DeviceLock<String>>>,
+    pub policy : DevicePolicy,
 }
 
 impl KitchenInterDimDevice {
@@ -19,7 +22,8 @@ impl KitchenInterDimDevice {
         info!("🌟🌟🌟🌟🌟 NEW KitchenInterDimDevice");
         let dl = DeviceLock::new( String::new());
         Self {
-            lock : Arc::new(RefCell::new( dl ))
+            lock : Arc::new(RefCell::new( dl )),
+            policy: DevicePolicy::default(),
         }
     }
     pub fn get_name() -> &'static str {
@@ -33,6 +37,14 @@ impl DynDevice for KitchenInterDimDevice {
         self.lock.clone()
     }
 
+    fn get_policy(&self) -> DevicePolicy {
+        self.policy
+    }
+
+    fn set_policy(&mut self, policy: DevicePolicy) {
+        self.policy = policy;
+    }
+
     fn setup(&mut self, _setup: bool) {
         // Nothing to do
     }
@@ -49,7 +61,7 @@ impl DynDevice for KitchenInterDimDevice {
         todo!()
     }
 
-    fn from_json_to_local(&self, msg: &str) -> Result<Box<dyn DeviceMessage>, String> {
+    fn from_json_to_local(&self, msg: &str) -> Result<Box<dyn DeviceMessage>, AvaError> {
         Ok(Box::new( InterDim::from_json(msg)? ))
     }
 
@@ -10,6 +10,8 @@ use crate::device_lock;
 use crate::device_lock::DeviceLock;
 use crate::device_message::{DeviceMessage, LampRGB};
 use crate::dyn_device::DynDevice;
+use crate::error::AvaError;
+use crate::policy::DevicePolicy;
 use crate::mqtt::publish;
 
 
@@ -19,6 +21,7 @@ pub (crate) const KITCHEN_LAMP: &str = "kitchen_lamp";
 pub (crate) struct KitchenLampDevice {
     pub lock : Arc<RefCell<DeviceLock<String>>>,
     pub setup : bool,
+    pub policy : DevicePolicy,
 }
 
 impl KitchenLampDevice {
@@ -28,6 +31,7 @@ impl KitchenLampDevice {
         Self {
             lock : Arc::new(RefCell::new( dl )),
             setup: false,
+            policy: DevicePolicy::default(),
         }
     }
 
@@ -43,6 +47,14 @@ impl DynDevice for KitchenLampDevice {
         self.lock.clone()
     }
 
+    fn get_policy(&self) -> DevicePolicy {
+        self.policy
+    }
+
+    fn set_policy(&mut self, policy: DevicePolicy) {
+        self.policy = policy;
+    }
+
     fn setup(&mut self, setup: bool) {
         self.setup = setup;
     }
@@ -62,7 +74,7 @@ impl DynDevice for KitchenLampDevice {
         // publish(&mut pub_stream, &format!("{}/get", &self.get_topic()), r#"{"color":{"x":"","y":""}}"#);
     }
 
-    fn from_json_to_local(&self, msg: &str) -> Result<Box<dyn DeviceMessage>, String> {
+    fn from_json_to_local(&self, msg: &str) -> Result<Box<dyn DeviceMessage>, AvaError> {
         Ok(Box::new( LampRGB::from_json(msg)? ))
     }
 
@@ -0,0 +1,76 @@
+use std::cell::RefCell;
+use std::sync::Arc;
+
+use log::info;
+
+use crate::device_lock::DeviceLock;
+use crate::device_message::{ActionEvent, DeviceMessage};
+use crate::dyn_device::DynDevice;
+use crate::error::AvaError;
+use crate::policy::DevicePolicy;
+
+pub (crate) const KITCHEN_REMOTE : &str = "kitchen_remote";
+
+///
+/// A zigbee remote : it only sends actions, it's never commanded and has no state
+///
+#[derive(Debug)]
+pub (crate) struct KitchenRemoteDevice {
+    pub lock : Arc<RefCell<DeviceLock<String>>>,
+    pub policy : DevicePolicy,
+}
+
+impl KitchenRemoteDevice {
+    pub(crate) fn new() -> Self {
+        info!("🌟🌟🌟🌟🌟 NEW KitchenRemoteDevice");
+        let dl = DeviceLock::new( String::new());
+        Self {lock : Arc::new(RefCell::new( dl )), policy: DevicePolicy::default() }
+    }
+
+    pub fn get_name() -> &'static str {
+        KITCHEN_REMOTE
+    }
+}
+
+impl DynDevice for KitchenRemoteDevice {
+
+    fn get_lock(&self) -> Arc<RefCell<DeviceLock<String>>> {
+        self.lock.clone()
+    }
+
+    fn get_policy(&self) -> DevicePolicy {
+        self.policy
+    }
+
+    fn set_policy(&mut self, policy: DevicePolicy) {
+        self.policy = policy;
+    }
+
+    fn setup(&mut self, _setup: bool) {
+        // Nothing to do
+    }
+
+    fn get_topic(&self) -> String {
+        format!("zigbee2mqtt/{}", Self::get_name())
+    }
+
+    fn is_init(&self) -> bool {
+        // Nothing to read from a remote
+        true
+    }
+
+    fn trigger_info(&self) -> Vec<u8> {
+        let msg = r#"{}"#;
+        msg.as_bytes().to_vec()
+    }
+
+    fn from_json_to_local(&self, msg: &str) -> Result<Box<dyn DeviceMessage>, AvaError> {
+        Ok(Box::new( ActionEvent::from_json(msg)? ))
+    }
+
+    fn to_local(&self, _origin_message : &Box<dyn DeviceMessage>, _last_message: &Box<dyn DeviceMessage>) -> Box<dyn DeviceMessage> {
+        // A remote is not the target of a loop
+        Box::new(ActionEvent::new())
+    }
+
+}
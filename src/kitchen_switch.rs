@@ -6,20 +6,23 @@ use log::info;
 use crate::device_lock::DeviceLock;
 use crate::device_message::{DeviceMessage, InterSwitch};
 use crate::dyn_device::DynDevice;
+use crate::error::AvaError;
+use crate::policy::DevicePolicy;
 
 pub (crate) const KITCHEN_SWITCH : &str = "kitchen_switch";
 
 #[derive(Debug)]
 pub (crate) struct KitchenSwitchDevice {
     pub setup : bool,
-    pub lock : Arc<RefCell<DeviceLock<String>>>
+    pub lock : Arc<RefCell<DeviceLock<String>>>,
+    pub policy : DevicePolicy,
 }
 
 impl KitchenSwitchDevice {
     pub(crate) fn new() -> Self {
         info!("🌟🌟🌟🌟🌟 NEW KitchenSwitchDevice");
         let dl = DeviceLock::new( String::new());
-        Self {setup: false,lock : Arc::new(RefCell::new( dl )) }
+        Self {setup: false,lock : Arc::new(RefCell::new( dl )), policy: DevicePolicy::default() }
     }
 
     pub fn get_name() -> &'static str {
@@ -33,6 +36,14 @@ impl DynDevice for KitchenSwitchDevice {
         self.lock.clone()
     }
 
+    fn get_policy(&self) -> DevicePolicy {
+        self.policy
+    }
+
+    fn set_policy(&mut self, policy: DevicePolicy) {
+        self.policy = policy;
+    }
+
     fn setup(&mut self, _setup: bool) {
         // Nothing to do
     }
@@ -52,7 +63,7 @@ impl DynDevice for KitchenSwitchDevice {
         // publish(&mut pub_stream, &format!("{}/get", &self.get_topic()), r#"{"state":""}"#);
     }
 
-    fn from_json_to_local(&self, msg: &str) -> Result<Box<dyn DeviceMessage>, String> {
+    fn from_json_to_local(&self, msg: &str) -> Result<Box<dyn DeviceMessage>, AvaError> {
         Ok(Box::new( InterSwitch::from_json(msg)? ))
     }
 
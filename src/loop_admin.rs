@@ -0,0 +1,62 @@
+use log::info;
+use serde_derive::*;
+
+use crate::error::AvaError;
+use crate::loops::HardLoop;
+use crate::origin::Origin;
+use crate::outbound::OutboundQueue;
+use crate::policy::CommandPolicy;
+
+const LOOP_ADMIN_PREFIX: &str = "ava/loop";
+
+/// ava/loop/+/set, the commands of all the loops
+pub (crate) fn loop_set_filter() -> String {
+    format!("{}/+/set", LOOP_ADMIN_PREFIX)
+}
+
+pub (crate) fn loop_set_topic(name: &str) -> String {
+    format!("{}/{}/set", LOOP_ADMIN_PREFIX, name)
+}
+
+fn loop_state_topic(name: &str) -> String {
+    format!("{}/{}/state", LOOP_ADMIN_PREFIX, name)
+}
+
+///
+/// Command received on ava/loop/<name>/set, ex : {"enabled": false}
+///
+#[derive(Deserialize, Debug)]
+struct LoopCommand {
+    enabled: Option<bool>,
+}
+
+#[derive(Serialize, Debug)]
+struct LoopState {
+    name: String,
+    enabled: bool,
+}
+
+///
+/// Apply the admin command to the loop, return true when the loop has changed
+///
+pub (crate) fn apply_loop_command(lp: &mut HardLoop, msg: &str) -> Result<bool, AvaError> {
+    let command: LoopCommand = serde_json::from_str(msg)?;
+    match command.enabled {
+        Some(enabled) if enabled != lp.enabled => {
+            info!("{} Loop [{}] is now {}", if enabled { "▶" } else { "⏸" }, &lp.name, if enabled { "enabled" } else { "disabled" });
+            lp.enabled = enabled;
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
+
+/// Retained, so the dashboards know the status of the loop at any time
+pub (crate) fn publish_loop_state(lp: &HardLoop, outbound: &mut OutboundQueue, origin: &Origin) {
+    let state = LoopState {
+        name: lp.name.clone(),
+        enabled: lp.enabled,
+    };
+    let policy = CommandPolicy { retain: true, ..CommandPolicy::default() };
+    outbound.push_json(&loop_state_topic(&lp.name), &state, &policy, origin);
+}
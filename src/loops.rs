@@ -1,68 +1,50 @@
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::ops::Deref;
 use std::sync::Arc;
 
 use log::info;
-use rumqttc::v5::AsyncClient;
 
 use crate::device_message::DeviceMessage;
+use crate::device_repo::find_device;
 use crate::dyn_device::DynDevice;
+use crate::error::AvaError;
+use crate::groups::{GroupMembers, GroupRegistry, LoopGroup};
 use crate::hall_lamp::HALL_LAMP;
 use crate::kitchen_inter_dim::KITCHEN_INTER_DIM;
 use crate::kitchen_lamp::KITCHEN_LAMP;
 use crate::kitchen_switch::KITCHEN_SWITCH;
+use crate::manual_override::is_overridden;
+use crate::origin::Origin;
+use crate::outbound::{OutboundCommand, OutboundQueue};
+use crate::policy::CommandPolicy;
+use crate::startup::{device_name, StartupPolicy};
 
 pub (crate) const KITCHEN_LOOP : &str = "KITCHEN_LOOP";
 pub (crate) const KITCHEN_LOOP_2 : &str = "KITCHEN_LOOP_2";
 
+// The zigbee2mqtt group of the kitchen and hall lamps
+const KITCHEN_LIGHTS : &str = "kitchen_lights";
+
 pub (crate) const TOO_HOT_LOOP : &str = "TOO_HOT_LOOP";
 pub (crate) const SENSOR_LOOP : &str = "SENSOR_LOOP";
 
-fn device_to_listen(device_repo: &HashMap<String, Arc<RefCell<dyn DynDevice>>>) -> Vec<Arc<RefCell<dyn DynDevice>>> {
-    vec![
-        device_repo.get(KITCHEN_INTER_DIM).unwrap().clone(),
-        device_repo.get(KITCHEN_LAMP).unwrap().clone(),
-        device_repo.get(HALL_LAMP).unwrap().clone(),
-        // device_repo.get(TEMP_BAIE_VITREE).unwrap().clone(),
-        // device_repo.get(TEMP_MEUBLE_TV).unwrap().clone()
-        device_repo.get(KITCHEN_SWITCH).unwrap().clone(),
-    ]
-}
-
-pub (crate) fn find_loops(topic: &str, all_loops: &mut Vec<HardLoop>) -> (Vec<HardLoop>, Option<Arc<RefCell<dyn DynDevice>>>)  {
-    let mut eligible_loops : Vec<HardLoop> = vec![];
-    let mut output_dev : Option<Arc<RefCell<dyn DynDevice>>> = None;
-
-    for lp in all_loops {
-        match lp.find_device_by_topic(topic) {
-            None => {}
-            Some(dev) => {
-                info!("Found topic in [{}] loop, topic=[{}]", & lp.get_name(), topic);
-                eligible_loops.push(lp.clone());
-                output_dev = Some(dev.clone());
-            }
-        }
-
-    }
-    (eligible_loops, output_dev)
-}
-
-pub (crate) fn build_loops(device_repo: &HashMap<String, Arc<RefCell<dyn DynDevice>>>) -> Vec<HardLoop> {
+pub (crate) fn build_loops(device_repo: &HashMap<String, Arc<RefCell<dyn DynDevice>>>) -> Result<Vec<HardLoop>, AvaError> {
 
     let kitchen_loop = HardLoop::new( KITCHEN_LOOP.to_string(),
                                       vec![
-                                          device_repo.get(KITCHEN_INTER_DIM).unwrap().clone(),
-                                          device_repo.get(KITCHEN_LAMP).unwrap().clone(),
-                                          device_repo.get(HALL_LAMP).unwrap().clone(),
-                                      ]);
+                                          find_device(device_repo, KITCHEN_INTER_DIM)?,
+                                          find_device(device_repo, KITCHEN_LAMP)?,
+                                          find_device(device_repo, HALL_LAMP)?,
+                                      ]).with_startup_policy(StartupPolicy::Leader(KITCHEN_LAMP))
+                                        .with_group(LoopGroup { name: KITCHEN_LIGHTS, members: GroupMembers::Configured(&[KITCHEN_LAMP, HALL_LAMP]) });
 
     let kitchen_loop_2 = HardLoop::new( KITCHEN_LOOP_2.to_string(),
                                       vec![
-                                          device_repo.get(KITCHEN_SWITCH).unwrap().clone(),
-                                          device_repo.get(KITCHEN_LAMP).unwrap().clone(),
-                                          device_repo.get(HALL_LAMP).unwrap().clone(),
-                                      ]);
+                                          find_device(device_repo, KITCHEN_SWITCH)?,
+                                          find_device(device_repo, KITCHEN_LAMP)?,
+                                          find_device(device_repo, HALL_LAMP)?,
+                                      ]).with_startup_policy(StartupPolicy::Majority);
 
 
     // let lamp_loop = HardLoop::new( KITCHEN_LOOP.to_string(),
@@ -82,13 +64,32 @@ pub (crate) fn build_loops(device_repo: &HashMap<String, Arc<RefCell<dyn DynDevi
     //                                      device_repo.get(TEMP_MEUBLE_TV).unwrap().clone(),
     //                                  ]);
 
-    vec![kitchen_loop, kitchen_loop_2/*, too_hot_loop, sensor_loop, lamp_loop*/]
+    // A loop can force the policy of the commands to its devices, ex :
+    // let heating_loop = HardLoop::new(...).with_command_policy(CommandPolicy { qos: QoS::ExactlyOnce, retain: false });
+    // and win over the other loops for the devices they share :
+    // let heating_loop = HardLoop::new(...).with_priority(10);
+    // A configured group is created and kept in line by AVA, the members of a group managed in zigbee2mqtt are read from the bridge :
+    // .with_group(LoopGroup { name: KITCHEN_LIGHTS, members: GroupMembers::FromBridge })
+
+    Ok(vec![kitchen_loop, kitchen_loop_2/*, too_hot_loop, sensor_loop, lamp_loop*/])
 }
 
 #[derive(Clone)]
 pub (crate) struct HardLoop {
     pub name : String,
     pub devices : Vec<Arc<RefCell<dyn DynDevice>>>,
+    // Topic and json of the last message propagated through the loop
+    pub last_state : Arc<RefCell<Option<(String, String)>>>,
+    // Overrides the command policy of the devices
+    pub command_policy : Option<CommandPolicy>,
+    // When loops share a device, the one with the highest priority commands it
+    pub priority : i32,
+    // How the devices are aligned after the initialization and after a reconnection
+    pub startup_policy : StartupPolicy,
+    // A disabled loop ignores the messages of its devices, see ava/loop/<name>/set
+    pub enabled : bool,
+    // The followers that get the same state are commanded at once through this group
+    pub group : Option<LoopGroup>,
 }
 
 impl HardLoop {
@@ -96,9 +97,42 @@ impl HardLoop {
         Self {
             name,
             devices,
+            last_state: Arc::new(RefCell::new(None)),
+            command_policy: None,
+            priority: 0,
+            startup_policy: StartupPolicy::default(),
+            enabled: true,
+            group: None,
         }
     }
 
+    fn with_group(mut self, group: LoopGroup) -> Self {
+        self.group = Some(group);
+        self
+    }
+
+    fn with_startup_policy(mut self, policy: StartupPolicy) -> Self {
+        self.startup_policy = policy;
+        self
+    }
+
+    #[allow(dead_code)]
+    fn with_priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    #[allow(dead_code)]
+    fn with_command_policy(mut self, policy: CommandPolicy) -> Self {
+        self.command_policy = Some(policy);
+        self
+    }
+
+    /// The policy of the loop if any, or the one of the device
+    pub (crate) fn command_policy_for(&self, device: &dyn DynDevice) -> CommandPolicy {
+        self.command_policy.unwrap_or_else(|| device.get_policy().command)
+    }
+
     pub fn get_name(&self) -> String {
         self.name.clone()
     }
@@ -117,17 +151,130 @@ impl HardLoop {
         None
     }
 
-    pub async fn loop_devices(&self, topic: &str, original_message: &Box<dyn DeviceMessage>, /*mut pub_stream: &mut TcpStream*/ mut client: &mut AsyncClient) {
+    ///
+    /// Send the message to the other devices of the loop, a failing device doesn't stop the others.
+    /// The devices already commanded for this event (by a loop with a higher priority) are skipped.
+    /// When the group of the loop can be used, its members get a single command.
+    /// Return the failures by device topic.
+    ///
+    pub async fn loop_devices(&self, topic: &str, original_message: &Box<dyn DeviceMessage>, /*mut pub_stream: &mut TcpStream*/ outbound: &mut OutboundQueue, origin: &Origin,
+                              groups: &GroupRegistry, targeted: &mut HashSet<String>) -> Vec<(String, AvaError)> {
+        let mut errors = vec![];
+        let origin = origin.for_loop(&self.name);
+        if let Ok(json_message) = original_message.to_json() {
+            self.last_state.replace(Some((topic.to_string(), json_message)));
+        }
+        let mut targets = vec![];
         for dev in self.get_devices() {
             info!("Loop the devices");
             let dd1 = dev.as_ref().borrow();
             let dd = dd1.deref();
             if &dd.get_topic() != topic {
-                info!("🚀 Device Topic of the loop: [{:?}]", &dd.get_topic());
-                dd.consume_message(&original_message, &mut client);
-                info!("🚩 End Device Topic of the loop: [{:?}]", &dd.get_topic());
+                if targeted.contains(&dd.get_topic()) {
+                    info!("🔀 Device [{}] already commanded for this event, skip it in loop [{}]", &dd.get_topic().to_uppercase(), &self.name);
+                    continue;
+                }
+                if !dd.is_available() {
+                    info!("📴 Device [{}] is offline, skip it", &dd.get_topic().to_uppercase());
+                    continue;
+                }
+                if is_overridden(dd) {
+                    info!("✋ Device [{}] is in override, skip it", &dd.get_topic().to_uppercase());
+                    continue;
+                }
+                targeted.insert(dd.get_topic());
+                targets.push(dev.clone());
+            }
+        }
+
+        let grouped = self.send_to_group(&targets, original_message, outbound, &origin, groups);
+        for dev in targets {
+            let dd1 = dev.as_ref().borrow();
+            let dd = dd1.deref();
+            if grouped.contains(&dd.get_topic()) {
+                continue;
+            }
+            info!("🚀 Device Topic of the loop: [{:?}]", &dd.get_topic());
+            if let Err(e) = dd.consume_message(&original_message, outbound, &origin, &self.command_policy_for(dd)) {
+                errors.push((dd.get_topic(), e));
             }
+            info!("🚩 End Device Topic of the loop: [{:?}]", &dd.get_topic());
         }
+        errors
+    }
+
+    ///
+    /// One command to the zigbee2mqtt group when all its members are targets and get the same state, so they switch together.
+    /// Each member still expects the echo of the command. Return the topics of the members commanded, empty when the group can't be used.
+    ///
+    fn send_to_group(&self, targets: &[Arc<RefCell<dyn DynDevice>>], original_message: &Box<dyn DeviceMessage>, outbound: &mut OutboundQueue,
+                     origin: &Origin, groups: &GroupRegistry) -> Vec<String> {
+        let (group, members) = match self.group.and_then(|g| groups.members(&g).map(|m| (g, m))) {
+            None => return vec![],
+            Some(gm) => gm,
+        };
+        let devices: Vec<_> = targets.iter().filter(|dev| members.contains(&device_name(dev.as_ref().borrow().deref()))).collect();
+        if members.is_empty() || devices.len() != members.len() {
+            return vec![];
+        }
+        // The errors show up again on the per device path
+        let commands: Vec<Option<String>> = devices.iter()
+            .map(|dev| dev.as_ref().borrow().command_for(original_message).ok().flatten())
+            .collect();
+        let json_message = match &commands[0] {
+            Some(json) if commands.iter().all(|c| c.as_ref() == Some(json)) => json.clone(),
+            _ => return vec![],
+        };
+
+        let first = devices[0].as_ref().borrow();
+        let policy = self.command_policy_for(first.deref());
+        let group_topic = match first.get_topic().rsplit_once('/') {
+            None => group.name.to_string(),
+            Some((base, _)) => format!("{}/{}", base, group.name),
+        };
+        info!("👪 Command the group [{}] of loop [{}], message : {:?}", &group_topic, &self.name, &json_message);
+        // A command still held by the rate limit of a member (or of the group) would be sent after the group one and undo it
+        for dev in &devices {
+            outbound.drop_waiting(&format!("{}/set", dev.as_ref().borrow().get_topic()));
+        }
+        outbound.drop_waiting(&format!("{}/set", &group_topic));
+        let locks = devices.iter().map(|dev| dev.as_ref().borrow().get_topic()).collect();
+        outbound.push(OutboundCommand::new(&format!("{}/set", &group_topic), &json_message, &policy, origin).with_locks(locks));
+        devices.iter().map(|dev| {
+            let dd = dev.as_ref().borrow();
+            dd.track_command(&json_message, origin, &policy);
+            dd.get_topic()
+        }).collect()
+    }
+
+    ///
+    /// Send the last state of the loop to a device that comes back online
+    ///
+    pub fn resync_device(&self, device: &Arc<RefCell<dyn DynDevice>>, outbound: &mut OutboundQueue, origin: &Origin) -> Result<(), AvaError> {
+        let last_state = self.last_state.borrow().clone();
+        let (source_topic, json_message) = match last_state {
+            None => {
+                info!("Nothing propagated in loop [{}] yet, no re-sync", &self.name);
+                return Ok(());
+            }
+            Some(state) => state
+        };
+
+        let dd1 = device.as_ref().borrow();
+        let dd = dd1.deref();
+        if dd.get_topic() == source_topic || is_overridden(dd) {
+            // The device itself gave the last state of the loop, or it's not driven by the loop for now
+            return Ok(());
+        }
+
+        let source = match self.find_device_by_topic(&source_topic) {
+            None => return Ok(()),
+            Some(source) => source
+        };
+        let original_message = source.as_ref().borrow().from_json_to_local(&json_message)
+            .map_err(|e| AvaError::State(format!("cannot read the last state of loop [{}], msg=<{}>, {}", &self.name, &json_message, e)))?;
+        info!("🔄 Re-sync device [{}] with loop [{}]", &dd.get_topic().to_uppercase(), &self.name);
+        dd.resync(&original_message, outbound, &origin.for_loop(&self.name), &self.command_policy_for(dd))
     }
 
 }
@@ -1,18 +1,41 @@
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::env;
+use std::ops::Deref;
+use std::process;
 use std::sync::Arc;
 use std::time::Duration;
 
-use log::info;
-use rumqttc::v5::{AsyncClient, MqttOptions};
+use log::{error, info};
+use rumqttc::v5::AsyncClient;
 use rumqttc::v5::mqttbytes::QoS;
 
+use crate::actions::{Actions, build_action_bindings};
+use crate::availability::availability_topic;
+use crate::ava_status::AvaStatus;
+use crate::breaker::{BreakerPolicy, CircuitBreakers};
+use crate::bridge::{Bridge, BRIDGE_STATE_TOPIC};
+use crate::coalescer::Coalescer;
+use crate::connection::ConnectionSettings;
+use crate::deadletter::{DEADLETTER_TOPIC, DeadLetterBox, dump_request_topic};
 use crate::device_repo::{build_device_repo, device_to_listen};
 use crate::dyn_device::DynDevice;
+use crate::error::{AvaError, ErrorCounters};
+use crate::group_sync::{GROUP_RESPONSE_FILTER, GroupSync};
+use crate::groups::{BRIDGE_DEVICES_TOPIC, BRIDGE_GROUPS_TOPIC};
 use crate::init_loop::{build_init_list, process_initialization_message};
+use crate::loop_admin::{loop_set_filter, publish_loop_state};
 use crate::loops::build_loops;
-use crate::processing::process_incoming_message;
+use crate::manual_override::publish_override_state;
+use crate::policy::{apply_device_policies, build_device_policies};
+use crate::origin::Origin;
+use crate::outbound::OutboundQueue;
+use crate::processing::{AvaContext, process_incoming_message};
+use crate::router::{build_router, wildcard_filter};
+use crate::shutdown::{EXIT_CONFIG_ERROR, EXIT_INIT_FAILED, shutdown, ShutdownReason, wait_for_signal};
+use crate::startup::enforce_startup_policies;
+use crate::state_store::load_state;
+use crate::timers::{build_timer_rules, Timers};
 
 mod hall_lamp;
 mod kitchen_lamp;
@@ -28,34 +51,141 @@ mod init_loop;
 mod processing;
 mod message_enum;
 mod generic_device;
+mod shutdown;
+mod ava_status;
+mod availability;
+mod bridge;
+mod origin;
+mod router;
+mod policy;
+mod connection;
+mod state_store;
+mod outbound;
+mod error;
+mod deadletter;
+mod comparison;
+mod twin;
+mod breaker;
+mod startup;
+mod loop_admin;
+mod manual_override;
+mod timers;
+mod coalescer;
+mod groups;
+mod group_sync;
+mod kitchen_remote;
+mod actions;
 
 const CLIENT_ID: &str = "ava-0.5.0";
+const STATE_FILE: &str = "ava_state.json";
+const SHUTDOWN_TIMEOUT: u64 = 5;
+const HEARTBEAT_INTERVAL: u64 = 60;
+const CLIENT_CAPACITY: usize = 10;
+const OUTBOUND_CAPACITY: usize = 100;
+const DEADLETTER_CAPACITY: usize = 50;
+const TRANSITIVE_HOPS: u32 = 0;
+const OVERRIDE_DURATION: u64 = 1800;
+const UTC_OFFSET: i64 = 0;
+const PERSIST_TIMERS: bool = false;
 
 #[derive(Debug, Clone)]
 pub struct Params {
-    pub server_addr : String,
+    pub connection : ConnectionSettings,
     pub client_id : String,
     pub channel_filters: Vec<(String, QoS)>,
     pub keep_alive :  u16,
+    pub state_file : String,
+    pub shutdown_timeout : u64,
+    pub heartbeat_interval : u64,
+    pub client_capacity : usize,
+    pub outbound_capacity : usize,
+    pub outbound_file : Option<String>,
+    pub deadletter_topic : String,
+    pub deadletter_capacity : usize,
+    pub transitive_hops : u32,
+    pub override_duration : Duration,
+    pub utc_offset : i64,
+    pub persist_timers : bool,
+}
+
+/// Read a parameter from the environment, or take the default value
+pub (crate) fn env_param(name: &str, default: &str) -> String {
+    env::var(name).unwrap_or_else(|_| default.to_string())
 }
 
 /// Build the list of channel to listen
-fn parse_params(device_repo: &HashMap<String, Arc<RefCell<dyn DynDevice>>>) -> Params {
+fn parse_params(device_repo: &HashMap<String, Arc<RefCell<dyn DynDevice>>>) -> Result<Params, AvaError> {
     let client_id = CLIENT_ID.to_string();
 
-    let mut channel_filters: Vec<(String, QoS)> = vec![];
-    for dev in device_to_listen(&device_repo) {
+    // One wildcard subscription per base topic (zigbee2mqtt/+), the router dispatches the messages.
+    // When the devices of a base topic don't share the same QoS, each one has its own subscription.
+    let mut by_filter: Vec<(String, Vec<(String, QoS)>)> = vec![];
+    for dev in device_to_listen(&device_repo)? {
         let dd = dev.as_ref().borrow();
         let topic = dd.get_topic();
-        channel_filters.push((topic, QoS::AtMostOnce));
+        let filter = wildcard_filter(&topic);
+        let qos = dd.get_policy().subscribe_qos;
+        match by_filter.iter_mut().find(|(f, _)| f == &filter) {
+            Some((_, devices)) => devices.push((topic, qos)),
+            None => by_filter.push((filter, vec![(topic, qos)])),
+        }
+    }
+
+    let deadletter_topic = env_param("AVA_DEADLETTER_TOPIC", DEADLETTER_TOPIC);
+    let mut channel_filters: Vec<(String, QoS)> = vec![
+        (BRIDGE_STATE_TOPIC.to_string(), QoS::AtMostOnce),
+        (BRIDGE_DEVICES_TOPIC.to_string(), QoS::AtMostOnce),
+        (BRIDGE_GROUPS_TOPIC.to_string(), QoS::AtMostOnce),
+        (GROUP_RESPONSE_FILTER.to_string(), QoS::AtMostOnce),
+        (dump_request_topic(&deadletter_topic), QoS::AtMostOnce),
+        (loop_set_filter(), QoS::AtLeastOnce),
+    ];
+    for (filter, devices) in by_filter {
+        let qos = devices[0].1;
+        if devices.iter().all(|(_, q)| *q == qos) {
+            channel_filters.push((availability_topic(&filter), qos));
+            channel_filters.push((filter, qos));
+        } else {
+            for (topic, qos) in devices {
+                channel_filters.push((availability_topic(&topic), qos));
+                channel_filters.push((topic, qos));
+            }
+        }
     }
 
-    Params {
-        server_addr : "raspberrypi.local".to_string(),
+    Ok(Params {
+        connection : ConnectionSettings::from_env()?,
         client_id,
         channel_filters,
         keep_alive : 30_000,
-    }
+        state_file : env_param("AVA_STATE_FILE", STATE_FILE),
+        shutdown_timeout : env_param("AVA_SHUTDOWN_TIMEOUT", &SHUTDOWN_TIMEOUT.to_string()).parse()
+            .map_err(|e| AvaError::Config(format!("invalid AVA_SHUTDOWN_TIMEOUT, {}", e)))?,
+        // 0 disables the heartbeat
+        heartbeat_interval : env_param("AVA_HEARTBEAT_INTERVAL", &HEARTBEAT_INTERVAL.to_string()).parse()
+            .map_err(|e| AvaError::Config(format!("invalid AVA_HEARTBEAT_INTERVAL, {}", e)))?,
+        client_capacity : env_param("AVA_CLIENT_CAPACITY", &CLIENT_CAPACITY.to_string()).parse()
+            .map_err(|e| AvaError::Config(format!("invalid AVA_CLIENT_CAPACITY, {}", e)))?,
+        outbound_capacity : env_param("AVA_OUTBOUND_CAPACITY", &OUTBOUND_CAPACITY.to_string()).parse()
+            .map_err(|e| AvaError::Config(format!("invalid AVA_OUTBOUND_CAPACITY, {}", e)))?,
+        // Empty, the outbound queue is kept in memory only
+        outbound_file : Some(env_param("AVA_OUTBOUND_FILE", "")).filter(|f| !f.is_empty()),
+        deadletter_topic,
+        deadletter_capacity : env_param("AVA_DEADLETTER_CAPACITY", &DEADLETTER_CAPACITY.to_string()).parse()
+            .map_err(|e| AvaError::Config(format!("invalid AVA_DEADLETTER_CAPACITY, {}", e)))?,
+        // 0, a change only reaches the loops of its source device
+        transitive_hops : env_param("AVA_TRANSITIVE_HOPS", &TRANSITIVE_HOPS.to_string()).parse()
+            .map_err(|e| AvaError::Config(format!("invalid AVA_TRANSITIVE_HOPS, {}", e)))?,
+        // Seconds a follower changed by hand stays out of its loops
+        override_duration : env_param("AVA_OVERRIDE_DURATION", &OVERRIDE_DURATION.to_string()).parse().map(Duration::from_secs)
+            .map_err(|e| AvaError::Config(format!("invalid AVA_OVERRIDE_DURATION, {}", e)))?,
+        // Minutes from UTC, for the overrides that end at midnight
+        utc_offset : env_param("AVA_UTC_OFFSET", &UTC_OFFSET.to_string()).parse()
+            .map_err(|e| AvaError::Config(format!("invalid AVA_UTC_OFFSET, {}", e)))?,
+        // Keep the running timers in the state file, so they still fire after a restart
+        persist_timers : env_param("AVA_PERSIST_TIMERS", &PERSIST_TIMERS.to_string()).parse()
+            .map_err(|e| AvaError::Config(format!("invalid AVA_PERSIST_TIMERS, {}", e)))?,
+    })
 }
 
 
@@ -69,61 +199,133 @@ async fn main() {
 
     info!("Building the device repository");
     let device_repo = build_device_repo();
-    let params = parse_params(&device_repo);
+    apply_device_policies(&device_repo, &build_device_policies());
+    let mut params = match parse_params(&device_repo) {
+        Ok(params) => params,
+        Err(e) => {
+            error!("💀 Invalid configuration, e={}", e);
+            process::exit(EXIT_CONFIG_ERROR);
+        }
+    };
+    let loops = build_init_list(&device_repo)
+        .and_then(|init_list| build_loops(&device_repo).map(|all_loops| (init_list, all_loops)))
+        .and_then(|(init_list, all_loops)| build_timer_rules(&device_repo).map(|rules| (init_list, all_loops, rules)))
+        .and_then(|(init_list, all_loops, rules)| build_action_bindings(&device_repo).map(|bindings| (init_list, all_loops, rules, bindings)));
+    let (init_list, mut all_loops, timer_rules, action_bindings) = match loops {
+        Ok(loops) => loops,
+        Err(e) => {
+            error!("💀 Invalid loops, e={}", e);
+            process::exit(EXIT_CONFIG_ERROR);
+        }
+    };
+    let state = load_state(&params.state_file);
+    state.restore(&device_repo);
+    state.restore_loops(&mut all_loops);
+    let mut timers = Timers::new(timer_rules);
+    if params.persist_timers {
+        timers.restore(&state.timers);
+    }
+    // The topics of the rules that are not devices of the loops, ex : a motion sensor
+    for topic in timers.topics() {
+        let covered = params.channel_filters.iter().any(|(f, _)| f == &topic || f == &wildcard_filter(&topic));
+        if !covered {
+            params.channel_filters.push((topic, QoS::AtMostOnce));
+        }
+    }
+    let status = AvaStatus::new(&params.client_id, params.heartbeat_interval);
 
     ///
 
-    let mut mqttoptions = MqttOptions::new(&params.client_id, &params.server_addr, 1883);
+    let mut mqttoptions = match params.connection.mqtt_options(&params.client_id) {
+        Ok(options) => options,
+        Err(e) => {
+            error!("💀 Invalid connection settings, e={}", e);
+            process::exit(EXIT_CONFIG_ERROR);
+        }
+    };
     mqttoptions.set_keep_alive(Duration::from_secs(params.keep_alive as u64));
     mqttoptions.set_clean_start(true);
-    mqttoptions.set_credentials("ava", "avatece3.X");
+    mqttoptions.set_last_will(status.last_will());
+
+    let (mut client, mut eventloop) = AsyncClient::new(mqttoptions, params.client_capacity);
 
-    let (mut client, mut eventloop) = AsyncClient::new(mqttoptions, 10);
+    // Queued now, sent right after the connection
+    status.publish_online(&client);
 
     for p in &params.channel_filters {
-        info!("Subscribe to [{}]", p.0);
-        client.subscribe(p.0.clone(), QoS::AtMostOnce).await.unwrap();
+        info!("Subscribe to [{}], qos={:?}", p.0, p.1);
+        if let Err(e) = client.subscribe(p.0.clone(), p.1).await {
+            error!("💀 Cannot subscribe to [{}], e={}", p.0, AvaError::from(e));
+            process::exit(EXIT_INIT_FAILED);
+        }
     }
 
-    // task::spawn(async move {
-    //     for i in 0..10 {
-    //
-    //         let msg : &str = "{\"brightness\":200, \"state\":\"ON\"}";
-    //         // let toto = String::from("mes données");
-    //         let octets = msg.as_bytes().to_vec();
-    //
-    //         let user_properties = vec![("a".to_string(), "b".to_string())];
-    //
-    //         let properties = PublishProperties {
-    //            // payload_format_indicator: Some(true),  // Indicateur de format de charge utile (UTF-8)
-    //             user_properties,
-    //             // Ajoutez d'autres propriétés selon vos besoins
-    //             ..Default::default()  // Utiliser les valeurs par défaut pour les autres propriétés
-    //         };
-    //
-    //         // Création de la structure Publish avec les propriétés
-    //         // let publish = Publish::new("zigbee2mqtt/denis", QoS::AtLeastOnce,
-    //         //                            octets, Some(properties));
-    //
-    //         client.publish_with_properties("zigbee2mqtt/kitchen_lamp/set", QoS::AtLeastOnce, false,
-    //                                        octets, properties).await.unwrap();
-    //         time::sleep(Duration::from_millis(100)).await;
-    //     }
-    // });
-
-    let mut init_list = build_init_list(&device_repo);
-    let mut all_loops = build_loops(&device_repo);
-
-    match process_initialization_message(&mut client, &mut eventloop, &mut init_list).await {
-        Ok(_) => {
-            info!("Process incoming messages");
-            let _ = process_incoming_message(&mut client, &mut eventloop, &mut all_loops).await;
-        }
-        Err(e) => {
-            panic!("{}", e);
+    let mut outbound = OutboundQueue::new(params.outbound_capacity, params.outbound_file.clone());
+    for dev in device_repo.values() {
+        let dd = dev.as_ref().borrow();
+        if let Some(interval) = dd.get_policy().rate_limit {
+            outbound.set_rate_limit(&format!("{}/set", dd.get_topic()), interval);
         }
     }
-    println!("Done!");
+
+    let actions = Actions::new(action_bindings);
+    let router = build_router(&all_loops, &timers.topics(), &actions.remotes(), &params.deadletter_topic);
+    let mut ctx = AvaContext {
+        device_repo,
+        init_list,
+        all_loops,
+        router,
+        bridge: Bridge::new(),
+        outbound,
+        errors: ErrorCounters::default(),
+        deadletters: DeadLetterBox::new(&params.deadletter_topic, params.deadletter_capacity),
+        breakers: CircuitBreakers::new(BreakerPolicy::default()),
+        timers,
+        coalescer: Coalescer::default(),
+        group_sync: GroupSync::default(),
+        actions,
+        reinit_until: None,
+        params,
+        status,
+    };
+
+    let reason = tokio::select! {
+        init = process_initialization_message(&mut client, &mut eventloop, &ctx.init_list, &mut ctx.bridge) => {
+            match init {
+                Ok(_) => None,
+                Err(e) => Some(ShutdownReason::InitFailed(e)),
+            }
+        }
+        reason = wait_for_signal() => Some(reason),
+    };
+
+    let reason = match reason {
+        None => {
+            info!("Process incoming messages");
+            // Connected by now, send the commands left by the previous run
+            ctx.outbound.set_connected(true);
+            let origin = Origin::new_cause(&ctx.status.client_id);
+            for lp in &ctx.all_loops {
+                publish_loop_state(lp, &mut ctx.outbound, &origin);
+            }
+            for dev in ctx.device_repo.values() {
+                let dd = dev.as_ref().borrow();
+                if dd.get_policy().manual_override.is_some() {
+                    publish_override_state(dd.deref(), &mut ctx.outbound, &origin);
+                }
+            }
+            // The groups of the bridge came with the initialization
+            ctx.group_sync.sync(&ctx.all_loops, &ctx.bridge.groups, &mut ctx.outbound, &origin);
+            enforce_startup_policies(&mut ctx);
+            ctx.outbound.flush(&client);
+            process_incoming_message(&mut client, &mut eventloop, &mut ctx).await
+        }
+        Some(reason) => reason,
+    };
+
+    shutdown(&client, &mut eventloop, &ctx.state(), &ctx.params, &ctx.status, &mut ctx.outbound, &reason).await;
+    info!("Done! exit code={}", reason.exit_code());
+    process::exit(reason.exit_code());
 }
 
 
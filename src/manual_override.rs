@@ -0,0 +1,117 @@
+use std::time::Duration;
+
+use log::info;
+use serde_derive::*;
+
+use crate::dyn_device::DynDevice;
+use crate::origin::Origin;
+use crate::outbound::OutboundQueue;
+use crate::policy::CommandPolicy;
+use crate::startup::device_name;
+
+const OVERRIDE_PREFIX: &str = "ava/override";
+const DAY_MILLIS: i64 = 24 * 3600 * 1000;
+
+///
+/// When a follower changed by hand goes back under the control of its loops.
+/// The override duration is a safety net for the source action, the midnight end ignores it.
+///
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+#[allow(dead_code)]
+pub (crate) enum OverrideEnd {
+    // After the override duration
+    #[default]
+    Timeout,
+    // At the next human action on a source of its loops (the dimmer, the switch)
+    SourceAction,
+    // At the next midnight, local time
+    Midnight,
+}
+
+///
+/// The loops don't drive a device in override
+///
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub (crate) struct ManualOverride {
+    pub end: OverrideEnd,
+    // Epoch millis
+    pub until: u64,
+}
+
+impl ManualOverride {
+    pub (crate) fn new(end: OverrideEnd, now: u64, duration: Duration, utc_offset_minutes: i64) -> Self {
+        let until = match end {
+            OverrideEnd::Timeout | OverrideEnd::SourceAction => now + duration.as_millis() as u64,
+            OverrideEnd::Midnight => next_midnight(now, utc_offset_minutes),
+        };
+        Self { end, until }
+    }
+}
+
+/// Epoch millis of the next local midnight
+fn next_midnight(now: u64, utc_offset_minutes: i64) -> u64 {
+    let offset = utc_offset_minutes * 60 * 1000;
+    let local = now as i64 + offset;
+    ((local / DAY_MILLIS + 1) * DAY_MILLIS - offset) as u64
+}
+
+#[derive(Serialize, Debug)]
+struct OverrideState {
+    device: String,
+    #[serde(rename = "override")]
+    active: bool,
+    end: Option<String>,
+    until: Option<u64>,
+}
+
+pub (crate) fn is_overridden(device: &dyn DynDevice) -> bool {
+    device.get_lock().as_ref().borrow().manual_override.is_some()
+}
+
+///
+/// Put the device in override : what AVA still expected from it is forgotten, the manual change wins
+///
+pub (crate) fn start_override(device: &dyn DynDevice, manual_override: ManualOverride, outbound: &mut OutboundQueue, origin: &Origin) {
+    info!("✋ Device {} changed by hand, in override ({:?}) until [{}]", &device.get_topic().to_uppercase(), manual_override.end, manual_override.until);
+    {
+        let lk = device.get_lock();
+        let mut dev_lock = lk.as_ref().borrow_mut();
+        dev_lock.desired = None;
+        dev_lock.release_locks();
+        dev_lock.manual_override = Some(manual_override);
+    }
+    publish_override_state(device, outbound, origin);
+}
+
+pub (crate) fn end_override(device: &dyn DynDevice, reason: &str, outbound: &mut OutboundQueue, origin: &Origin) {
+    let ended = device.get_lock().as_ref().borrow_mut().manual_override.take().is_some();
+    if ended {
+        info!("🤝 Device {} is back in its loops, {}", &device.get_topic().to_uppercase(), reason);
+        publish_override_state(device, outbound, origin);
+    }
+}
+
+/// End the override of the device when its time is over
+pub (crate) fn expire_override(device: &dyn DynDevice, now: u64, outbound: &mut OutboundQueue, origin: &Origin) {
+    let expired = device.get_lock().as_ref().borrow().manual_override.is_some_and(|o| now >= o.until);
+    if expired {
+        end_override(device, "override over", outbound, origin);
+    }
+}
+
+///
+/// Retained, so the dashboards see which devices are in override.
+/// Also published at startup to clear the overrides of the previous run.
+///
+pub (crate) fn publish_override_state(device: &dyn DynDevice, outbound: &mut OutboundQueue, origin: &Origin) {
+    let manual_override = device.get_lock().as_ref().borrow().manual_override;
+    let state = OverrideState {
+        device: device.get_topic(),
+        active: manual_override.is_some(),
+        end: manual_override.map(|o| format!("{:?}", o.end)),
+        until: manual_override.map(|o| o.until),
+    };
+    let topic = format!("{}/{}", OVERRIDE_PREFIX, device_name(device));
+    let policy = CommandPolicy { retain: true, ..CommandPolicy::default() };
+    outbound.push_json(&topic, &state, &policy, origin);
+}
@@ -1,4 +1,5 @@
 use crate::device_message::{InterDim, InterSwitch, LampRGB};
+use crate::error::AvaError;
 use crate::message_enum::MessageEnum::{INTER_DIMMER, INTER_SWITCH, LAMP_RGB};
 
 /// Object by enums
@@ -41,7 +42,7 @@ impl MessageEnum {
             }
         }
     }
-    pub (crate) fn json_to_local(&self) -> Result<MessageEnum, String> {
+    pub (crate) fn json_to_local(&self) -> Result<MessageEnum, AvaError> {
         match self {
             LAMP_RGB((msg, _)) => {
                 Ok(LAMP_RGB((msg.clone(), LampRGB::from_json(msg)?)))
@@ -0,0 +1,97 @@
+use rumqttc::v5::mqttbytes::v5::PublishProperties;
+use uuid::Uuid;
+
+pub (crate) const ORIGIN_AVA: &str = "ava";
+
+const ORIGIN_KEY: &str = "origin";
+const INSTANCE_KEY: &str = "ava_instance";
+const LOOP_KEY: &str = "loop";
+const CAUSATION_KEY: &str = "causation_id";
+
+///
+/// Who caused a message, carried in the MQTT5 user properties of the publishes.
+/// All the commands triggered by the same incoming event share the same causation id.
+///
+#[derive(Debug, Clone, PartialEq)]
+pub (crate) struct Origin {
+    pub origin: Option<String>,
+    pub instance: Option<String>,
+    pub loop_name: Option<String>,
+    pub causation_id: String,
+}
+
+impl Origin {
+
+    /// A new event caused by the current AVA instance
+    pub (crate) fn new_cause(instance: &str) -> Self {
+        Self {
+            origin: Some(ORIGIN_AVA.to_string()),
+            instance: Some(instance.to_string()),
+            loop_name: None,
+            causation_id: Uuid::new_v4().to_string(),
+        }
+    }
+
+    ///
+    /// The origin of the commands AVA sends in reaction to an incoming message.
+    /// The causation id of the incoming message is kept when there is one.
+    ///
+    pub (crate) fn caused_by(incoming: &Option<Origin>, instance: &str) -> Self {
+        let mut origin = Self::new_cause(instance);
+        if let Some(o) = incoming {
+            origin.causation_id = o.causation_id.clone();
+        }
+        origin
+    }
+
+    pub (crate) fn for_loop(&self, loop_name: &str) -> Self {
+        Self {
+            loop_name: Some(loop_name.to_string()),
+            ..self.clone()
+        }
+    }
+
+    pub (crate) fn is_ava(&self) -> bool {
+        self.origin.as_deref() == Some(ORIGIN_AVA)
+    }
+
+    pub (crate) fn is_other_instance(&self, instance: &str) -> bool {
+        self.is_ava() && self.instance.as_deref() != Some(instance)
+    }
+
+    pub (crate) fn to_properties(&self) -> PublishProperties {
+        let mut user_properties = vec![];
+        if let Some(o) = &self.origin {
+            user_properties.push((ORIGIN_KEY.to_string(), o.clone()));
+        }
+        if let Some(i) = &self.instance {
+            user_properties.push((INSTANCE_KEY.to_string(), i.clone()));
+        }
+        if let Some(l) = &self.loop_name {
+            user_properties.push((LOOP_KEY.to_string(), l.clone()));
+        }
+        user_properties.push((CAUSATION_KEY.to_string(), self.causation_id.clone()));
+
+        PublishProperties {
+            user_properties,
+            ..Default::default()
+        }
+    }
+
+    /// Read the origin of an incoming message, None when nobody tagged it (ex : zigbee2mqtt state)
+    pub (crate) fn from_properties(properties: &Option<PublishProperties>) -> Option<Self> {
+        let properties = properties.as_ref()?;
+        let find = |key: &str| {
+            properties.user_properties.iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.clone())
+        };
+        let causation_id = find(CAUSATION_KEY)?;
+        Some(Self {
+            origin: find(ORIGIN_KEY),
+            instance: find(INSTANCE_KEY),
+            loop_name: find(LOOP_KEY),
+            causation_id,
+        })
+    }
+}
@@ -0,0 +1,419 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{error, info, warn};
+use rumqttc::v5::AsyncClient;
+use rumqttc::v5::mqttbytes::{qos, QoS};
+use rumqttc::v5::mqttbytes::v5::PublishProperties;
+use serde::Serialize;
+use serde_derive::*;
+
+use crate::origin::Origin;
+use crate::policy::CommandPolicy;
+
+pub (crate) fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+///
+/// A command waiting to be handed to the mqtt client
+///
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub (crate) struct OutboundCommand {
+    pub topic: String,
+    pub payload: String,
+    pub qos: u8,
+    pub retain: bool,
+    pub user_properties: Vec<(String, String)>,
+    pub created_at: u64,
+    // Epoch millis after which the command is no longer relevant
+    pub expires_at: Option<u64>,
+    // Topics of the devices locked until the echo of the command
+    #[serde(default)]
+    pub locks: Vec<String>,
+}
+
+impl OutboundCommand {
+    pub (crate) fn new(topic: &str, payload: &str, policy: &CommandPolicy, origin: &Origin) -> Self {
+        let created_at = now_millis();
+        Self {
+            topic: topic.to_string(),
+            payload: payload.to_string(),
+            qos: policy.qos as u8,
+            retain: policy.retain,
+            user_properties: origin.to_properties().user_properties,
+            created_at,
+            expires_at: policy.expiry.map(|d| created_at + d.as_millis() as u64),
+            locks: vec![],
+        }
+    }
+
+    /// The devices that wait for the echo of the command
+    pub (crate) fn with_locks(mut self, locks: Vec<String>) -> Self {
+        self.locks = locks;
+        self
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|limit| now > limit)
+    }
+}
+
+///
+/// Bounded queue of the commands produced by the loops.
+/// The commands are only handed to the client while the broker is reachable,
+/// the stale ones are dropped at that time. The queue can be saved on disk to survive a restart,
+/// it's written when the broker goes away and until it's empty again, not on every command.
+/// A rate limited topic gets at most one command per interval, the others wait in the queue.
+/// A command also goes through the rate limits of the devices it locks (ex : the command of their group).
+/// A dropped command will never be echoed, the devices it locked are given back with take_released.
+///
+#[derive(Debug)]
+pub (crate) struct OutboundQueue {
+    commands: VecDeque<OutboundCommand>,
+    capacity: usize,
+    connected: bool,
+    file: Option<String>,
+    // Minimum interval between 2 commands, by topic
+    rate_limits: HashMap<String, Duration>,
+    // Epoch millis of the last command handed to the client, for the rate limited topics
+    last_sent: HashMap<String, u64>,
+    // Topics of the devices locked by the dropped commands, one per lock
+    released: Vec<String>,
+    // Number of commands in the file
+    on_disk: usize,
+}
+
+impl OutboundQueue {
+    pub (crate) fn new(capacity: usize, file: Option<String>) -> Self {
+        let commands = match &file {
+            None => VecDeque::new(),
+            Some(path) => Self::load(path),
+        };
+        Self {
+            on_disk: commands.len(),
+            commands,
+            capacity,
+            connected: false,
+            file,
+            rate_limits: HashMap::new(),
+            last_sent: HashMap::new(),
+            released: vec![],
+        }
+    }
+
+    pub (crate) fn set_rate_limit(&mut self, topic: &str, interval: Duration) {
+        info!("📤 Rate limit [{}] to one command every {}ms", topic, interval.as_millis());
+        self.rate_limits.insert(topic.to_string(), interval);
+    }
+
+    /// The topic of the command and the ones of the devices it locks
+    fn limited_topics(command: &OutboundCommand) -> Vec<String> {
+        let mut topics = vec![command.topic.clone()];
+        topics.extend(command.locks.iter().map(|lock| format!("{}/set", lock)).filter(|t| *t != command.topic));
+        topics
+    }
+
+    /// Epoch millis from which the command can be sent
+    fn next_send(&self, command: &OutboundCommand) -> u64 {
+        Self::limited_topics(command).iter()
+            .map(|topic| match (self.rate_limits.get(topic), self.last_sent.get(topic)) {
+                (Some(interval), Some(last)) => last + interval.as_millis() as u64,
+                _ => 0,
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    fn load(path: &str) -> VecDeque<OutboundCommand> {
+        match fs::read_to_string(path) {
+            Ok(data) => {
+                let commands: VecDeque<OutboundCommand> = serde_json::from_str(&data).unwrap_or_else(|e| {
+                    warn!("Cannot read the outbound queue [{}], start with an empty queue, e={}", path, e);
+                    VecDeque::new()
+                });
+                info!("📤 [{}] commands reloaded from [{}]", commands.len(), path);
+                commands
+            }
+            Err(_) => VecDeque::new(),
+        }
+    }
+
+    pub (crate) fn save(&mut self) {
+        if let Some(path) = &self.file {
+            match serde_json::to_string(&self.commands) {
+                Ok(data) => {
+                    match fs::write(path, data) {
+                        Ok(_) => self.on_disk = self.commands.len(),
+                        Err(e) => error!("💀 Cannot save the outbound queue in [{}], e={}", path, e),
+                    }
+                }
+                Err(e) => {
+                    error!("💀 Cannot serialize the outbound queue, e={}", e);
+                }
+            }
+        }
+    }
+
+    pub (crate) fn push(&mut self, command: OutboundCommand) {
+        if self.commands.len() >= self.capacity {
+            if let Some(dropped) = self.commands.pop_front() {
+                warn!("📤 Outbound queue full, drop the oldest command for [{}] <{}>", &dropped.topic, &dropped.payload);
+                self.released.extend(dropped.locks);
+            }
+        }
+        self.commands.push_back(command);
+        // The commands only wait while the broker is unreachable
+        if !self.connected {
+            self.save();
+        }
+    }
+
+    /// Push the value in json (events, alerts, states of AVA)
+    pub (crate) fn push_json<T: Serialize>(&mut self, topic: &str, value: &T, policy: &CommandPolicy, origin: &Origin) {
+        match serde_json::to_string(value) {
+            Ok(data) => self.push(OutboundCommand::new(topic, &data, policy, origin)),
+            Err(e) => error!("💀 Cannot build the message for [{}], e={}", topic, e),
+        }
+    }
+
+    ///
+    /// Push the command of a device. On a rate limited topic, it replaces the command still waiting for the same topic, if any :
+    /// only the latest state is worth sending. Return true when a waiting command was replaced.
+    ///
+    pub (crate) fn push_latest(&mut self, command: OutboundCommand) -> bool {
+        if self.rate_limits.contains_key(&command.topic) {
+            if let Some(waiting) = self.commands.iter_mut().find(|c| c.topic == command.topic) {
+                info!("📤 Replace the waiting command for [{}] <{}> by <{}>", &command.topic, &waiting.payload, &command.payload);
+                *waiting = command;
+                if !self.connected {
+                    self.save();
+                }
+                return true;
+            }
+        }
+        self.push(command);
+        false
+    }
+
+    ///
+    /// Drop the commands waiting for the topic, a command sent another way overtakes them (ex : to the group of the device)
+    ///
+    pub (crate) fn drop_waiting(&mut self, topic: &str) {
+        let (dropped, kept): (VecDeque<OutboundCommand>, VecDeque<OutboundCommand>) = std::mem::take(&mut self.commands).into_iter()
+            .partition(|c| c.topic == topic);
+        self.commands = kept;
+        if dropped.is_empty() {
+            return;
+        }
+        for command in dropped {
+            info!("📤 Drop the waiting command for [{}] <{}>, overtaken", &command.topic, &command.payload);
+            self.released.extend(command.locks);
+        }
+        if !self.connected {
+            self.save();
+        }
+    }
+
+    /// The devices whose command was dropped, once
+    pub (crate) fn take_released(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.released)
+    }
+
+    pub (crate) fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    pub (crate) fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    pub (crate) fn set_connected(&mut self, connected: bool) {
+        if self.connected == connected {
+            return;
+        }
+        info!("📤 Broker {}, [{}] commands waiting", if connected { "reachable" } else { "unreachable" }, self.commands.len());
+        self.connected = connected;
+        if !connected {
+            self.save();
+        }
+    }
+
+    /// Time left before a command held by its rate limit can be sent, none when nothing waits for it
+    pub (crate) fn next_delay(&self, now: u64) -> Option<Duration> {
+        if !self.connected {
+            return None;
+        }
+        self.commands.iter()
+            .map(|c| self.next_send(c))
+            .filter(|at| *at > now)
+            .min()
+            .map(|at| Duration::from_millis(at - now))
+    }
+
+    ///
+    /// Hand the waiting commands to the client, drop the expired ones, keep the ones held by their rate limit
+    ///
+    pub (crate) fn flush(&mut self, client: &AsyncClient) {
+        if !self.connected || self.commands.is_empty() {
+            return;
+        }
+        let now = now_millis();
+        let mut held = VecDeque::new();
+        while let Some(command) = self.commands.pop_front() {
+            if command.is_expired(now) {
+                warn!("⌛ Drop the stale command for [{}] <{}>, {}ms old", &command.topic, &command.payload, now - command.created_at);
+                self.released.extend(command.locks);
+                continue;
+            }
+            if self.next_send(&command) > now {
+                held.push_back(command);
+                continue;
+            }
+            let properties = PublishProperties {
+                user_properties: command.user_properties.clone(),
+                ..Default::default()
+            };
+            let qos = qos(command.qos).unwrap_or(QoS::AtLeastOnce);
+            if let Err(e) = client.try_publish_with_properties(&command.topic, qos, command.retain, command.payload.clone(), properties) {
+                // The request channel is full, keep the command for the next flush
+                warn!("Cannot hand the command for [{}] to the client, e={:?}", &command.topic, e);
+                self.commands.push_front(command);
+                break;
+            }
+            for topic in Self::limited_topics(&command) {
+                if self.rate_limits.contains_key(&topic) {
+                    self.last_sent.insert(topic, now);
+                }
+            }
+        }
+        // The held commands were ahead of the ones left
+        while let Some(command) = held.pop_back() {
+            self.commands.push_front(command);
+        }
+        // Until the file is empty, the sent commands must not come back after a restart
+        if self.on_disk > 0 {
+            self.save();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rumqttc::v5::MqttOptions;
+
+    use super::*;
+
+    const LAMP: &str = "zigbee2mqtt/hall_lamp";
+    const LAMP_SET: &str = "zigbee2mqtt/hall_lamp/set";
+    const OTHER_SET: &str = "zigbee2mqtt/kitchen_lamp/set";
+
+    fn command(topic: &str, payload: &str) -> OutboundCommand {
+        OutboundCommand::new(topic, payload, &CommandPolicy::default(), &Origin::new_cause("test"))
+            .with_locks(vec![topic.trim_end_matches("/set").to_string()])
+    }
+
+    fn payloads(queue: &OutboundQueue) -> Vec<&str> {
+        queue.commands.iter().map(|c| c.payload.as_str()).collect()
+    }
+
+    // The client is never connected, it only keeps the requests in its channel
+    fn client() -> (AsyncClient, rumqttc::v5::EventLoop) {
+        AsyncClient::new(MqttOptions::new("test", "localhost", 1883), 10)
+    }
+
+    #[test]
+    fn expired_command_releases_its_lock() {
+        let (client, _eventloop) = client();
+        let mut queue = OutboundQueue::new(10, None);
+        queue.set_connected(true);
+        let mut stale = command(LAMP_SET, "A");
+        stale.expires_at = Some(stale.created_at - 1);
+        queue.push(stale);
+        queue.flush(&client);
+        assert_eq!(queue.len(), 0);
+        assert_eq!(queue.take_released(), vec![LAMP.to_string()]);
+        assert!(queue.take_released().is_empty());
+    }
+
+    #[test]
+    fn full_queue_releases_the_lock_of_the_oldest() {
+        let mut queue = OutboundQueue::new(1, None);
+        queue.push(command(LAMP_SET, "A"));
+        queue.push(command(OTHER_SET, "B"));
+        assert_eq!(payloads(&queue), vec!["B"]);
+        assert_eq!(queue.take_released(), vec![LAMP.to_string()]);
+    }
+
+    #[test]
+    fn push_latest_replaces_the_waiting_command() {
+        let mut queue = OutboundQueue::new(10, None);
+        // Not rate limited, the commands add up
+        assert!(!queue.push_latest(command(OTHER_SET, "A")));
+        assert!(!queue.push_latest(command(OTHER_SET, "B")));
+        queue.set_rate_limit(LAMP_SET, Duration::from_millis(250));
+        assert!(!queue.push_latest(command(LAMP_SET, "C")));
+        assert!(queue.push_latest(command(LAMP_SET, "D")));
+        assert_eq!(payloads(&queue), vec!["A", "B", "D"]);
+        assert!(queue.take_released().is_empty());
+    }
+
+    #[test]
+    fn rate_limit_holds_the_command_in_place() {
+        let (client, _eventloop) = client();
+        let mut queue = OutboundQueue::new(10, None);
+        queue.set_rate_limit(LAMP_SET, Duration::from_secs(60));
+        queue.set_connected(true);
+        queue.push(command(LAMP_SET, "A"));
+        queue.flush(&client);
+        assert_eq!(queue.len(), 0);
+
+        queue.push(command(LAMP_SET, "B"));
+        queue.push(command(OTHER_SET, "C"));
+        queue.push(command(LAMP_SET, "D"));
+        queue.flush(&client);
+        assert_eq!(payloads(&queue), vec!["B", "D"]);
+        assert!(queue.next_delay(now_millis()).is_some());
+    }
+
+    #[test]
+    fn group_command_goes_through_the_rate_limit_of_its_members() {
+        let (client, _eventloop) = client();
+        let mut queue = OutboundQueue::new(10, None);
+        queue.set_rate_limit(LAMP_SET, Duration::from_secs(60));
+        queue.set_connected(true);
+        let group = || command("zigbee2mqtt/hall/set", "G").with_locks(vec![LAMP.to_string(), OTHER_SET.trim_end_matches("/set").to_string()]);
+        queue.push(group());
+        queue.flush(&client);
+        assert_eq!(queue.len(), 0);
+
+        // The group command counted for the lamp
+        queue.push(command(LAMP_SET, "A"));
+        queue.push(group());
+        queue.push(command(OTHER_SET, "B"));
+        queue.flush(&client);
+        assert_eq!(payloads(&queue), vec!["A", "G"]);
+    }
+
+    #[test]
+    fn nothing_sent_while_disconnected() {
+        let (client, _eventloop) = client();
+        let mut queue = OutboundQueue::new(10, None);
+        queue.push(command(LAMP_SET, "A"));
+        queue.flush(&client);
+        assert_eq!(queue.len(), 1);
+        assert!(queue.next_delay(now_millis()).is_none());
+    }
+
+    #[test]
+    fn drop_waiting_releases_the_locks() {
+        let mut queue = OutboundQueue::new(10, None);
+        queue.push(command(LAMP_SET, "A"));
+        queue.push(command(OTHER_SET, "B"));
+        queue.push(command(LAMP_SET, "C"));
+        queue.drop_waiting(LAMP_SET);
+        assert_eq!(payloads(&queue), vec!["B"]);
+        assert_eq!(queue.take_released(), vec![LAMP.to_string(), LAMP.to_string()]);
+    }
+}
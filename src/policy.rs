@@ -0,0 +1,115 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::info;
+use rumqttc::v5::mqttbytes::QoS;
+
+use crate::comparison::ComparisonPolicy;
+use crate::dyn_device::DynDevice;
+use crate::hall_lamp::HALL_LAMP;
+use crate::kitchen_inter_dim::KITCHEN_INTER_DIM;
+use crate::kitchen_lamp::KITCHEN_LAMP;
+use crate::kitchen_switch::KITCHEN_SWITCH;
+use crate::manual_override::OverrideEnd;
+use crate::twin::ReconcilePolicy;
+
+const LAMP_COMMAND_EXPIRY: Duration = Duration::from_secs(5);
+// The lamps round the brightness and the color temperature they are given
+const LAMP_TOLERANCES: &[(&str, f64)] = &[("brightness", 2.0), ("color_temp", 2.0)];
+const DIMMER_TOLERANCES: &[(&str, f64)] = &[("brightness", 2.0)];
+// Rotating the dimmer sends a burst of brightness messages
+const DIMMER_COALESCE_WINDOW: Duration = Duration::from_millis(300);
+// What a zigbee lamp can take without flooding the network
+const LAMP_RATE_LIMIT: Duration = Duration::from_millis(250);
+
+///
+/// QoS and retain flag of the commands AVA sends, a loop can override the device one.
+/// A command older than its expiry is not sent anymore, none means it never expires.
+///
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub (crate) struct CommandPolicy {
+    pub qos: QoS,
+    pub retain: bool,
+    pub expiry: Option<Duration>,
+}
+
+impl Default for CommandPolicy {
+    fn default() -> Self {
+        Self {
+            qos: QoS::AtLeastOnce,
+            retain: false,
+            expiry: None,
+        }
+    }
+}
+
+///
+/// How AVA talks with a device : QoS of the subscription to its state and policy of its commands.
+/// Critical actuators (heating plug, water valve) want QoS 1/2, chatty sensors stay at QoS 0.
+///
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub (crate) struct DevicePolicy {
+    pub subscribe_qos: QoS,
+    pub command: CommandPolicy,
+    pub comparison: ComparisonPolicy,
+    pub reconcile: ReconcilePolicy,
+    // A follower goes in override when it's changed by hand, none for the sources of the loops
+    pub manual_override: Option<OverrideEnd>,
+    // Window in which the messages of the device are merged, only the first and the latest ones are processed
+    pub coalesce: Option<Duration>,
+    // Minimum interval between 2 commands to the device
+    pub rate_limit: Option<Duration>,
+}
+
+impl Default for DevicePolicy {
+    fn default() -> Self {
+        Self {
+            subscribe_qos: QoS::AtMostOnce,
+            command: CommandPolicy::default(),
+            comparison: ComparisonPolicy::default(),
+            reconcile: ReconcilePolicy::default(),
+            manual_override: None,
+            coalesce: None,
+            rate_limit: None,
+        }
+    }
+}
+
+pub (crate) fn build_device_policies() -> HashMap<String, DevicePolicy> {
+    let mut policies : HashMap<String, DevicePolicy> = HashMap::new();
+    policies.insert(KITCHEN_SWITCH.to_owned(), DevicePolicy::default());
+    policies.insert(KITCHEN_INTER_DIM.to_owned(), DevicePolicy {
+        comparison: ComparisonPolicy::with_tolerances(DIMMER_TOLERANCES),
+        coalesce: Some(DIMMER_COALESCE_WINDOW),
+        ..DevicePolicy::default()
+    });
+    // Switching a light on minutes after the click is worse than not switching it at all
+    let lamp = DevicePolicy {
+        command: CommandPolicy { expiry: Some(LAMP_COMMAND_EXPIRY), ..CommandPolicy::default() },
+        comparison: ComparisonPolicy::with_tolerances(LAMP_TOLERANCES),
+        rate_limit: Some(LAMP_RATE_LIMIT),
+        ..DevicePolicy::default()
+    };
+    // The kitchen lamp leads KITCHEN_LOOP and is the target of the remote : changed by hand, it drives its loops
+    policies.insert(KITCHEN_LAMP.to_owned(), lamp);
+    policies.insert(HALL_LAMP.to_owned(), DevicePolicy { manual_override: Some(OverrideEnd::Timeout), ..lamp });
+    // A lamp changed by hand can also wait for the next click on the dimmer, or for the night :
+    // policies.insert(HALL_LAMP.to_owned(), DevicePolicy { manual_override: Some(OverrideEnd::Midnight), ..lamp });
+    // policies.insert(HEATING_PLUG.to_owned(), DevicePolicy {
+    //     subscribe_qos: QoS::AtLeastOnce,
+    //     command: CommandPolicy { qos: QoS::ExactlyOnce, retain: false, expiry: None },
+    //     ..DevicePolicy::default()
+    // });
+    policies
+}
+
+pub (crate) fn apply_device_policies(device_repo: &HashMap<String, Arc<RefCell<dyn DynDevice>>>, policies: &HashMap<String, DevicePolicy>) {
+    for (name, policy) in policies {
+        if let Some(dev) = device_repo.get(name) {
+            info!("Policy for device [{}] : {:?}", name, policy);
+            dev.as_ref().borrow_mut().set_policy(*policy);
+        }
+    }
+}
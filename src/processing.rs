@@ -1,83 +1,727 @@
 use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::ops::Deref;
 use std::sync::Arc;
-use log::{error, info};
-use rumqttc::v5::{AsyncClient, Event, EventLoop, Incoming};
+use std::time::Duration;
+use log::{debug, error, info};
+use rumqttc::v5::{AsyncClient, ConnectionError, Event, EventLoop, Incoming};
+use rumqttc::v5::mqttbytes::v5::Filter;
+use serde_json::{json, Value};
+use tokio::time;
+use crate::actions::{Actions, ActionTarget, ramp_brightness};
+use crate::availability::{Availability, device_topic_of};
+use crate::ava_status::AvaStatus;
+use crate::breaker::CircuitBreakers;
+use crate::bridge::Bridge;
+use crate::coalescer::{Coalescer, HeldMessage};
+use crate::deadletter::DeadLetterBox;
+use crate::device_repo::find_device;
 use crate::dyn_device::DynDevice;
+use crate::error::{AvaError, ErrorCounters};
+use crate::group_sync::GroupSync;
+use crate::loop_admin::{apply_loop_command, publish_loop_state};
 use crate::loops::HardLoop;
+use crate::manual_override::{end_override, expire_override, ManualOverride, OverrideEnd, start_override};
+use crate::origin::Origin;
+use crate::outbound::{now_millis, OutboundCommand, OutboundQueue};
+use crate::policy::CommandPolicy;
+use crate::Params;
+use crate::router::{Handler, TopicRouter};
+use crate::shutdown::{ShutdownReason, wait_for_signal};
+use crate::startup::enforce_startup_policies;
+use crate::state_store::{AvaState, save_state};
+use crate::timers::Timers;
+use crate::twin::reconcile;
 
+// Pause before polling again a broker that cannot be reached, the event loop reconnects on the next poll
+const RECONNECT_DELAY: Duration = Duration::from_secs(2);
+// How often the desired and reported states of the devices are compared
+const RECONCILE_PERIOD: Duration = Duration::from_secs(1);
+// The client may not take the subscriptions right after a reconnection, they are asked again after that
+const RESUBSCRIBE_RETRY: Duration = Duration::from_millis(500);
+// A device that never answers after a restart of zigbee2mqtt is not waited for longer
+const REINIT_TIMEOUT: Duration = Duration::from_secs(10);
 
-pub (crate) fn find_loops(topic: &str, all_loops: &mut Vec<HardLoop>) -> (Vec<HardLoop>, Option<Arc<RefCell<dyn DynDevice>>>)  {
-    let mut eligible_loops : Vec<HardLoop> = vec![];
-    let mut output_dev : Option<Arc<RefCell<dyn DynDevice>>> = None;
+///
+/// Everything AVA needs to process the messages, apart from the mqtt client and its event loop
+///
+pub (crate) struct AvaContext {
+    pub params: Params,
+    pub status: AvaStatus,
+    pub device_repo: HashMap<String, Arc<RefCell<dyn DynDevice>>>,
+    pub init_list: Vec<Arc<RefCell<dyn DynDevice>>>,
+    pub all_loops: Vec<HardLoop>,
+    pub router: TopicRouter<Handler>,
+    pub bridge: Bridge,
+    pub outbound: OutboundQueue,
+    pub errors: ErrorCounters,
+    pub deadletters: DeadLetterBox,
+    pub breakers: CircuitBreakers,
+    pub timers: Timers,
+    pub coalescer: Coalescer,
+    pub group_sync: GroupSync,
+    pub actions: Actions,
+    // Epoch millis at which the initialization after a bridge restart gives up, none when no initialization is running
+    pub reinit_until: Option<u64>,
+}
+
+impl AvaContext {
+    /// What is saved on disk, the timers only when they are persisted
+    pub (crate) fn state(&self) -> AvaState {
+        let mut state = AvaState::from_repo(&self.device_repo, &self.all_loops);
+        if self.params.persist_timers {
+            state.timers = self.timers.pending().clone();
+        }
+        state
+    }
+}
+
+///
+/// The enabled loops of the handlers, each loop once, the highest priority first (then in the configuration order)
+///
+fn select_loops<'a>(indexes: &[usize], all_loops: &'a [HardLoop]) -> Vec<&'a HardLoop> {
+    let mut loops: Vec<&HardLoop> = vec![];
+    for i in indexes {
+        if let Some(lp) = all_loops.get(*i) {
+            if !lp.enabled {
+                info!("⏸ Loop [{}] is disabled", & lp.get_name());
+                continue;
+            }
+            info!("Found topic in [{}] loop", & lp.get_name());
+            loops.push(lp);
+        }
+    }
+    loops.sort_by_key(|lp| std::cmp::Reverse(lp.priority));
+    loops
+}
+
+///
+/// The device of the topic in the loops of the handlers, disabled or not : its state is kept up to date while its loops are disabled
+///
+fn find_loop_device(indexes: &[usize], all_loops: &[HardLoop], topic: &str) -> Option<Arc<RefCell<dyn DynDevice>>> {
+    indexes.iter().filter_map(|i| all_loops.get(*i)).find_map(|lp| lp.find_device_by_topic(topic))
+}
+
+///
+/// Store the availability of the device and re-sync it with its loop when it comes back online
+///
+fn process_availability_message(topic: &str, device_topic: &str, msg: &str, indexes: &[usize], ctx: &mut AvaContext) {
+    let availability = match Availability::from_payload(msg) {
+        None => {
+            let e = AvaError::Parse(format!("unknown availability for device {}, msg=<{}>", &device_topic.to_uppercase(), msg));
+            ctx.errors.record(&e);
+            ctx.deadletters.post(topic, msg, Some(device_topic), &e, &mut ctx.outbound, &Origin::new_cause(&ctx.status.client_id));
+            return;
+        }
+        Some(a) => a
+    };
+
+    let loops = select_loops(indexes, &ctx.all_loops);
+    match find_loop_device(indexes, &ctx.all_loops, device_topic) {
+        None => {
+            info!("No device to process the availability message");
+        }
+        Some(dev) => {
+            let back_online = dev.as_ref().borrow().update_availability(availability);
+            if back_online {
+                // One re-sync is enough, take the first loop that knows a state
+                if let Some(lp) = loops.iter().find(|lp| lp.last_state.borrow().is_some()) {
+                    if let Err(e) = lp.resync_device(&dev, &mut ctx.outbound, &Origin::new_cause(&ctx.status.client_id)) {
+                        ctx.errors.record(&e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+///
+/// Enable or disable a loop, publish its new state and save it right away
+///
+fn process_loop_command(topic: &str, msg: &str, index: usize, ctx: &mut AvaContext, origin: &Origin) {
+    let lp = match ctx.all_loops.get_mut(index) {
+        None => return,
+        Some(lp) => lp,
+    };
+    match apply_loop_command(lp, msg) {
+        Ok(true) => {
+            publish_loop_state(lp, &mut ctx.outbound, origin);
+            if let Err(e) = save_state(&ctx.params.state_file, &ctx.state()) {
+                ctx.errors.record(&e);
+            }
+        }
+        // Same state, publish it again for the one who asked
+        Ok(false) => publish_loop_state(lp, &mut ctx.outbound, origin),
+        Err(e) => {
+            let e = AvaError::Parse(format!("invalid command for loop [{}], msg=<{}>, {}", &lp.name, msg, e));
+            ctx.errors.record(&e);
+            ctx.deadletters.post(topic, msg, None, &e, &mut ctx.outbound, origin);
+        }
+    }
+}
+
+///
+/// Run the message of a device through all the loops it belongs to, unless a circuit breaker is open.
+/// One event, one propagation : the message is processed once for the device and each target gets one command,
+/// from the loop with the highest priority.
+/// Only the traffic that follows AVA (messages tagged by an instance, reports of a device it commanded within the window
+/// of the breakers, echoed or not) counts for the breakers, the hand of a human makes no storm.
+///
+async fn process_device_message(topic: &str, msg: &str, indexes: &[usize], ctx: &mut AvaContext, origin: &Origin, from_ava: bool) {
+    let loops = select_loops(indexes, &ctx.all_loops);
+    match find_loop_device(indexes, &ctx.all_loops, topic) {
+        None => {
+            info!("No device to process the message");
+        }
+        Some(dev) => {
+            info!("Receiver device found !");
+            let dd1 = dev.as_ref().borrow();
+            let dd = dd1.deref();
+            let now = now_millis();
+
+            // Change the msg into the DeviceMessage box of the ad hoc device (the original device)
+            let original_message = match dd.merge_incoming(msg).and_then(|merged| dd.from_json_to_local(&merged)) {
+                Ok(om) => {om}
+                Err(e) => {
+                    let e = AvaError::Parse(format!("cannot parse the message locally for device {}, msg=<{}>, {}", &dd.get_topic().to_uppercase(), msg, e));
+                    ctx.errors.record(&e);
+                    ctx.deadletters.post(topic, msg, Some(&dd.get_topic()), &e, &mut ctx.outbound, origin);
+                    return
+                }
+            };
+            let json_message = original_message.to_json().unwrap_or_else(|_| msg.to_string());
+
+            let commanded_at = dd.get_lock().as_ref().borrow().commanded_at;
+            let ava_caused = from_ava || ctx.breakers.follows_command(commanded_at, now);
+            if ava_caused {
+                ctx.breakers.record_device(topic, &json_message, now, &mut ctx.outbound, origin);
+            }
+            // A suspended device still keeps its state and its locks up to date, only the propagation stops
+            let suspended = !ctx.breakers.allow_device(topic, now);
+
+            match dd.process_and_continue(&original_message) {
+                Ok(true) if suspended => {}
+                Ok(true) => {
+                    // A message tagged by AVA is not a change by hand
+                    if let Some(end) = dd.get_policy().manual_override.filter(|_| !from_ava) {
+                        // Not the echo of a command, someone changed the follower by hand : the loops leave it alone
+                        let manual_override = ManualOverride::new(end, now, ctx.params.override_duration, ctx.params.utc_offset);
+                        start_override(dd, manual_override, &mut ctx.outbound, origin);
+                        return;
+                    }
+                    end_source_action_overrides(&loops, &mut ctx.outbound, origin);
+                    let mut targeted = HashSet::new();
+                    targeted.insert(topic.to_string());
+                    for lp in &loops {
+                        info!("Before Looping");
+                        if ava_caused {
+                            ctx.breakers.record_loop(&lp.name, &json_message, now, &mut ctx.outbound, origin);
+                        }
+                        if !ctx.breakers.allow_loop(&lp.name, now) {
+                            continue;
+                        }
+                        for (device, e) in lp.loop_devices(topic, &original_message, &mut ctx.outbound, origin, &ctx.bridge.groups, &mut targeted).await {
+                            ctx.errors.record(&e);
+                            ctx.deadletters.post(topic, msg, Some(&device), &e, &mut ctx.outbound, origin);
+                            targeted.remove(&device);
+                        }
+                    }
+                    if ctx.params.transitive_hops > 0 {
+                        let frontier = targeted.iter().filter(|t| *t != topic).cloned().collect();
+                        let visited = indexes.iter().copied().collect();
+                        propagate_transitively(frontier, visited, &mut targeted, ctx, origin, now).await;
+                    }
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    ctx.errors.record(&e);
+                    ctx.deadletters.post(topic, msg, Some(&dd.get_topic()), &e, &mut ctx.outbound, origin);
+                }
+            }
+        }
+    }
+}
+
+///
+/// A human action on a source gives back to the loops the followers waiting for it
+///
+fn end_source_action_overrides(loops: &[&HardLoop], outbound: &mut OutboundQueue, origin: &Origin) {
+    for lp in loops {
+        for dev in &lp.devices {
+            let dd = dev.as_ref().borrow();
+            let waiting = dd.get_lock().as_ref().borrow().manual_override.is_some_and(|o| o.end == OverrideEnd::SourceAction);
+            if waiting {
+                end_override(dd.deref(), "source action", outbound, origin);
+            }
+        }
+    }
+}
+
+///
+/// Turn the action of a remote into its targets. The action is an event : the remote keeps no state of it.
+///
+async fn process_action_message(topic: &str, msg: &str, ctx: &mut AvaContext, origin: &Origin) {
+    let action = ctx.device_repo.values()
+        .find(|dev| dev.as_ref().borrow().get_topic() == topic)
+        .ok_or_else(|| AvaError::Config(format!("no remote for topic [{}]", topic)))
+        .and_then(|dev| dev.as_ref().borrow().from_json_to_local(msg))
+        .and_then(|message| message.as_action_event()
+            .map(|event| event.action.clone().unwrap_or_default())
+            .ok_or_else(|| AvaError::Parse(format!("the message of [{}] is not an action", topic))));
+    let action = match action {
+        Ok(action) => action,
+        Err(e) => {
+            ctx.errors.record(&e);
+            ctx.deadletters.post(topic, msg, Some(topic), &e, &mut ctx.outbound, origin);
+            return;
+        }
+    };
+    // zigbee2mqtt may clear the action right after it, or send the battery level alone
+    if action.is_empty() {
+        return;
+    }
+    info!("🎮 Action [{}] on [{}]", &action, &topic.to_uppercase());
+    for target in ctx.actions.on_action(topic, &action, now_millis()) {
+        run_action(target, ctx, origin).await;
+    }
+}
+
+async fn run_action(target: ActionTarget, ctx: &mut AvaContext, origin: &Origin) {
+    let done = match target {
+        ActionTarget::Loop { device, payload } => command_device(device, &payload.to_string(), true, ctx, origin).await,
+        ActionTarget::Scene(states) => {
+            for (device, payload) in states {
+                if let Err(e) = command_device(device, &payload.to_string(), false, ctx, origin).await {
+                    ctx.errors.record(&e);
+                }
+            }
+            Ok(())
+        }
+        ActionTarget::Timer { name, op } => ctx.timers.apply(name, op, now_millis()),
+        ActionTarget::RampStart { device, step } => {
+            ctx.actions.start_ramp(device, step, now_millis());
+            Ok(())
+        }
+        ActionTarget::RampStop => {
+            ctx.actions.stop_ramp();
+            Ok(())
+        }
+        ActionTarget::RampStep { device, step } => ramp_step(device, step, ctx, origin).await,
+    };
+    if let Err(e) = done {
+        ctx.errors.record(&e);
+    }
+}
+
+///
+/// Move the brightness of the device by one step, the ramp stops at the limits
+///
+async fn ramp_step(device: &str, step: i64, ctx: &mut AvaContext, origin: &Origin) -> Result<(), AvaError> {
+    let current = {
+        let dev = find_device(&ctx.device_repo, device)?;
+        let dd = dev.as_ref().borrow();
+        // The step before may not be echoed yet, the ramp goes on from what was asked
+        let desired = dd.get_lock().as_ref().borrow().desired.as_ref().map(|d| d.message.clone());
+        let state = match desired {
+            Some(message) => message,
+            None => dd.last_message()?.to_json()?,
+        };
+        let last: Value = serde_json::from_str(&state)?;
+        last.get("brightness").and_then(|b| b.as_i64()).unwrap_or(0)
+    };
+    let brightness = match ramp_brightness(current, step) {
+        None => {
+            ctx.actions.stop_ramp();
+            return Ok(());
+        }
+        Some(brightness) => brightness,
+    };
+    command_device(device, &json!({"state": "ON", "brightness": brightness}).to_string(), true, ctx, origin).await
+}
 
-    for lp in all_loops {
-        match lp.find_device_by_topic(topic) {
-            None => {}
-            Some(dev) => {
-                info!("Found topic in [{}] loop, topic=[{}]", & lp.get_name(), topic);
-                eligible_loops.push(lp.clone());
-                output_dev = Some(dev.clone());
+///
+/// Set the device to the state asked by a remote, and carry it to the other devices of its loops.
+/// An explicit action gives the device back to its loops.
+///
+async fn command_device(name: &str, payload: &str, through_loops: bool, ctx: &mut AvaContext, origin: &Origin) -> Result<(), AvaError> {
+    let (topic, message) = {
+        let dev = find_device(&ctx.device_repo, name)?;
+        let dd1 = dev.as_ref().borrow();
+        let dd = dd1.deref();
+        end_override(dd, "remote action", &mut ctx.outbound, origin);
+        let message = dd.merge_incoming(payload).and_then(|merged| dd.from_json_to_local(&merged))?;
+        // Always sent : a step of a ramp comes while the echo of the step before may be on its way
+        dd.resync(&message, &mut ctx.outbound, origin, &dd.get_policy().command)?;
+        (dd.get_topic(), message)
+    };
+    if !through_loops {
+        return Ok(());
+    }
+
+    let indexes: Vec<usize> = ctx.all_loops.iter().enumerate()
+        .filter(|(_, lp)| lp.find_device_by_topic(&topic).is_some())
+        .map(|(i, _)| i)
+        .collect();
+    let loops = select_loops(&indexes, &ctx.all_loops);
+    end_source_action_overrides(&loops, &mut ctx.outbound, origin);
+    let mut targeted = HashSet::new();
+    targeted.insert(topic.clone());
+    for lp in loops {
+        for (device, e) in lp.loop_devices(&topic, &message, &mut ctx.outbound, origin, &ctx.bridge.groups, &mut targeted).await {
+            ctx.errors.record(&e);
+            targeted.remove(&device);
+        }
+    }
+    Ok(())
+}
+
+///
+/// Carry the change on, hop by hop, to the loops that share a device with the loops already done.
+/// The new state of the shared device is the message of the next hop.
+/// A loop is run once per event and a device commanded once, so the cycles stop by themselves.
+///
+async fn propagate_transitively(mut frontier: Vec<String>, mut visited: HashSet<usize>, targeted: &mut HashSet<String>,
+                                ctx: &mut AvaContext, origin: &Origin, now: u64) {
+    for hop in 1..=ctx.params.transitive_hops {
+        let mut next = vec![];
+        for device_topic in frontier {
+            let indexes: Vec<usize> = ctx.all_loops.iter().enumerate()
+                .filter(|(i, lp)| !visited.contains(i) && lp.find_device_by_topic(&device_topic).is_some())
+                .map(|(i, _)| i)
+                .collect();
+            if indexes.is_empty() {
+                continue;
+            }
+            visited.extend(indexes.iter().copied());
+
+            let loops = select_loops(&indexes, &ctx.all_loops);
+            let dev = match loops.first().and_then(|lp| lp.find_device_by_topic(&device_topic)) {
+                None => continue,
+                Some(dev) => dev,
+            };
+            let message = match dev.as_ref().borrow().last_message() {
+                Ok(m) => m,
+                Err(e) => {
+                    ctx.errors.record(&e);
+                    continue;
+                }
+            };
+            let json_message = message.to_json().unwrap_or_default();
+
+            for lp in loops {
+                info!("⛓ Hop {} : [{}] carries the change to loop [{}]", hop, &device_topic.to_uppercase(), &lp.name);
+                ctx.breakers.record_loop(&lp.name, &json_message, now, &mut ctx.outbound, origin);
+                if !ctx.breakers.allow_loop(&lp.name, now) {
+                    continue;
+                }
+                let before = targeted.clone();
+                for (device, e) in lp.loop_devices(&device_topic, &message, &mut ctx.outbound, origin, &ctx.bridge.groups, targeted).await {
+                    ctx.errors.record(&e);
+                    ctx.deadletters.post(&device_topic, &json_message, Some(&device), &e, &mut ctx.outbound, origin);
+                    targeted.remove(&device);
+                }
+                next.extend(targeted.difference(&before).cloned());
             }
         }
+        if next.is_empty() {
+            break;
+        }
+        frontier = next;
+    }
+}
 
+///
+/// All the subscriptions in a single request, return false when the client could not take it
+///
+fn subscribe_again(client: &AsyncClient, ctx: &mut AvaContext) -> bool {
+    let filters: Vec<Filter> = ctx.params.channel_filters.iter().map(|(filter, qos)| Filter::new(filter.clone(), *qos)).collect();
+    match client.try_subscribe_many(filters) {
+        Ok(_) => true,
+        Err(e) => {
+            error!("💀 Cannot subscribe again, retry in {}ms", RESUBSCRIBE_RETRY.as_millis());
+            ctx.errors.record(&AvaError::from(e));
+            false
+        }
     }
-    (eligible_loops, output_dev)
 }
 
+///
+/// After a restart of zigbee2mqtt, ask the devices of the init list for their state again.
+/// The messages keep being processed while the answers come, see reinit_device.
+///
+fn start_reinit(ctx: &mut AvaContext, origin: &Origin) {
+    info!("🌉 zigbee2mqtt has restarted, initialize the devices again");
+    for dev in &ctx.init_list {
+        let mut dd = dev.as_ref().borrow_mut();
+        dd.reset();
+        let data = String::from_utf8_lossy(&dd.trigger_info()).to_string();
+        ctx.outbound.push(OutboundCommand::new(&format!("{}/get", &dd.get_topic()), &data, &CommandPolicy::default(), origin));
+    }
+    ctx.reinit_until = Some(now_millis() + REINIT_TIMEOUT.as_millis() as u64);
+}
 
 ///
+/// Initialize the device with its message when it's waiting for it, return false when the message must be processed as usual.
+/// The initialization is over once all the devices that are not offline have answered.
 ///
+fn reinit_device(topic: &str, msg: &str, ctx: &mut AvaContext) -> bool {
+    if ctx.reinit_until.is_none() {
+        return false;
+    }
+    let waiting = match ctx.init_list.iter().find(|dev| dev.as_ref().borrow().get_topic() == topic) {
+        Some(dev) if !dev.as_ref().borrow().is_init() => dev,
+        _ => return false,
+    };
+    waiting.as_ref().borrow_mut().init(topic, msg);
+    if ctx.init_list.iter().all(|dev| dev.as_ref().borrow().is_init() || !dev.as_ref().borrow().is_available()) {
+        info!("🌉 Devices initialized again");
+        ctx.reinit_until = None;
+    }
+    true
+}
+
+///
+/// Hand the commands to the client. The devices locked by a dropped command won't get its echo, their lock is released.
 ///
-pub async fn process_incoming_message(mut client: &mut AsyncClient, mut eventloop: &mut EventLoop, mut all_loops: &mut Vec<HardLoop>)  {
+fn flush_outbound(client: &AsyncClient, ctx: &mut AvaContext) {
+    ctx.outbound.flush(client);
+    for topic in ctx.outbound.take_released() {
+        if let Some(dev) = ctx.device_repo.values().find(|dev| dev.as_ref().borrow().get_topic() == topic) {
+            info!("🔓 Command to [{}] dropped, no echo to wait for", &topic.to_uppercase());
+            dev.as_ref().borrow().get_lock().borrow_mut().release_one();
+        }
+    }
+}
+
+///
+/// Process the incoming messages until a signal is received or the broker refuses the connection.
+/// While the broker is unreachable, the commands wait in the outbound queue.
+///
+pub (crate) async fn process_incoming_message(client: &mut AsyncClient, eventloop: &mut EventLoop, ctx: &mut AvaContext) -> ShutdownReason {
     // let delay = time::Duration::from_millis(10);
 
     info!(">>> loop 0");
 
-    while let Ok(notification) = eventloop.poll().await {
+    let stop_signal = wait_for_signal();
+    tokio::pin!(stop_signal);
+
+    let heartbeat_period = ctx.status.heartbeat_period();
+    let mut heartbeat = time::interval(heartbeat_period.unwrap_or(Duration::from_secs(1)));
+    let mut reconcile_tick = time::interval(RECONCILE_PERIOD);
+    // Clean start, the subscriptions are gone with the previous session until the client takes them again
+    let mut resubscribe = false;
+
+    loop {
+        if resubscribe {
+            resubscribe = !subscribe_again(client, ctx);
+        }
+        let now = now_millis();
+        let timer_delay = ctx.timers.next_delay(now);
+        let coalesce_delay = ctx.coalescer.next_delay(now);
+        let send_delay = ctx.outbound.next_delay(now);
+        let action_delay = ctx.actions.next_delay(now);
+        let reinit_delay = ctx.reinit_until.map(|until| Duration::from_millis(until.saturating_sub(now)));
+        let notification = tokio::select! {
+            reason = &mut stop_signal => {
+                info!("🛑 Stop processing incoming messages");
+                return reason;
+            }
+            // No heartbeat while the broker is unreachable, the requests would fill the channel of the client
+            _ = heartbeat.tick(), if heartbeat_period.is_some() && ctx.outbound.is_connected() => {
+                ctx.status.publish_heartbeat(client, &ctx.errors);
+                continue;
+            }
+            _ = time::sleep(RESUBSCRIBE_RETRY), if resubscribe => {
+                continue;
+            }
+            // The latest state of the bursts, at the end of their window
+            _ = time::sleep(coalesce_delay.unwrap_or_default()), if coalesce_delay.is_some() => {
+                for held in ctx.coalescer.take_due(now_millis()) {
+                    info!("🌊 End of the window of [{}], message: <{}>", &held.topic, &held.msg);
+                    process_device_message(&held.topic, &held.msg, &held.indexes, ctx, &held.origin, held.from_ava).await;
+                }
+                flush_outbound(client, ctx);
+                continue;
+            }
+            // Commands held by the rate limit of their device
+            _ = time::sleep(send_delay.unwrap_or_default()), if send_delay.is_some() => {
+                flush_outbound(client, ctx);
+                continue;
+            }
+            // The devices that did not answer get their state with their next report
+            _ = time::sleep(reinit_delay.unwrap_or_default()), if reinit_delay.is_some() => {
+                ctx.reinit_until = None;
+                let e = AvaError::Transport(format!("devices not initialized again after {}s", REINIT_TIMEOUT.as_secs()));
+                ctx.errors.record(&e);
+                continue;
+            }
+            // End of the click windows and steps of the ramp
+            _ = time::sleep(action_delay.unwrap_or_default()), if action_delay.is_some() => {
+                let origin = Origin::new_cause(&ctx.status.client_id);
+                for target in ctx.actions.take_due(now_millis()) {
+                    run_action(target, ctx, &origin).await;
+                }
+                flush_outbound(client, ctx);
+                continue;
+            }
+            // The commands of the timers wait in the outbound queue while the broker is unreachable
+            _ = time::sleep(timer_delay.unwrap_or_default()), if timer_delay.is_some() => {
+                let origin = Origin::new_cause(&ctx.status.client_id);
+                for (_, e) in ctx.timers.fire_due(now_millis(), &mut ctx.outbound, &origin) {
+                    ctx.errors.record(&e);
+                }
+                if ctx.params.persist_timers {
+                    if let Err(e) = save_state(&ctx.params.state_file, &ctx.state()) {
+                        ctx.errors.record(&e);
+                    }
+                }
+                flush_outbound(client, ctx);
+                continue;
+            }
+            // No retry while the broker is unreachable, the devices could not answer anyway
+            _ = reconcile_tick.tick(), if ctx.outbound.is_connected() => {
+                let now = now_millis();
+                let origin = Origin::new_cause(&ctx.status.client_id);
+                for dev in ctx.device_repo.values() {
+                    let dd = dev.as_ref().borrow();
+                    expire_override(dd.deref(), now, &mut ctx.outbound, &origin);
+                    reconcile(dd.deref(), &mut ctx.outbound, now);
+                }
+                ctx.breakers.tick(now, &mut ctx.outbound, &origin);
+                flush_outbound(client, ctx);
+                continue;
+            }
+            event = eventloop.poll() => {
+                match event {
+                    Ok(notification) => notification,
+                    Err(ConnectionError::ConnectionRefused(code)) => {
+                        error!("💀 Connection refused by the broker, code={:?}", code);
+                        return ShutdownReason::ConnectionLost(format!("connection refused, code={:?}", code));
+                    }
+                    Err(e) => {
+                        error!("💀 Broker unreachable, retry in {}s, e={}", RECONNECT_DELAY.as_secs(), e);
+                        ctx.outbound.set_connected(false);
+                        time::sleep(RECONNECT_DELAY).await;
+                        continue;
+                    }
+                }
+            }
+        };
+
         info!(">>> loop 1");
         match notification {
             Event::Incoming(Incoming::Publish(publish)) => {
                 // Votre logique de traitement des messages ici
 
-                let msg = std::str::from_utf8(&publish.payload).unwrap();
-                let topic = std::str::from_utf8(publish.topic.as_ref()).unwrap(); // TODO
+                let (topic, msg) = match (std::str::from_utf8(publish.topic.as_ref()), std::str::from_utf8(&publish.payload)) {
+                    (Ok(topic), Ok(msg)) => (topic, msg),
+                    (Err(e), _) | (_, Err(e)) => {
+                        ctx.errors.record(&AvaError::from(e));
+                        continue;
+                    }
+                };
 
-                info!("🧶 Publish on topic: [{}], message: <{}>", topic, msg);
+                let handlers = ctx.router.route(topic);
+                if handlers.is_empty() {
+                    debug!("No handler for topic [{}]", topic);
+                    continue;
+                }
 
-                let (loops, opt_device) = find_loops(&topic, &mut all_loops);
+                info!("🧶 Publish on topic: [{}], message: <{}>", topic, msg);
 
-                match opt_device {
+                let incoming_origin = Origin::from_properties(&publish.properties);
+                match &incoming_origin {
+                    Some(o) if o.is_other_instance(&ctx.status.client_id) => {
+                        info!("🤖 Message caused by another AVA instance [{:?}], loop={:?}, cause={}", &o.instance, &o.loop_name, &o.causation_id);
+                    }
+                    Some(o) if o.is_ava() => {
+                        info!("🤖 Message caused by AVA, loop={:?}, cause={}", &o.loop_name, &o.causation_id);
+                    }
+                    Some(o) => {
+                        info!("Message caused by [{:?}], cause={}", &o.origin, &o.causation_id);
+                    }
                     None => {
-                        info!("No device to process the message");
+                        info!("🙋 Message without origin, human action or device report");
                     }
-                    Some(dev) => {
-                        info!("Receiver device found !");
-                        let dd1 = dev.as_ref().borrow();
-                        let dd = dd1.deref();
-                        for lp in loops {
-                            info!("Before Looping");
-
-                            // Change the msg into the DeviceMessage box of the ad hoc device (the original device)
-                            let original_message = match dd.from_json_to_local(msg) {
-                                Ok(om) => {om}
-                                Err(e) => {
-                                    error!("💀 Cannot parse the message locally for device {}, msg=<{}>, \n e={}", &dd.get_topic().to_uppercase(), msg, e);
-                                    continue
-                                }
-                            };
+                }
+                let origin = Origin::caused_by(&incoming_origin, &ctx.status.client_id);
 
-                            if dd.process_and_continue(&original_message) {
-                                lp.loop_devices(&topic, &original_message, &mut client).await;
+                let mut loop_indexes = vec![];
+                let mut availability_indexes = vec![];
+                for handler in handlers {
+                    match handler {
+                        Handler::BridgeState => {
+                            if ctx.bridge.update(msg) {
+                                info!("🌉 zigbee2mqtt has restarted, initialize the devices again");
+                                ctx.group_sync.forget_pending();
+                                for dev in &ctx.init_list {
+                                    dev.as_ref().borrow_mut().reset();
+                                }
+                                start_reinit(ctx, &origin);
                             }
                         }
+                        Handler::BridgeDevices | Handler::BridgeGroups => {
+                            let updated = if handler == Handler::BridgeDevices { ctx.bridge.groups.update_devices(msg) } else { ctx.bridge.groups.update_groups(msg) };
+                            match updated {
+                                Ok(_) => ctx.group_sync.sync(&ctx.all_loops, &ctx.bridge.groups, &mut ctx.outbound, &origin),
+                                Err(e) => ctx.errors.record(&e),
+                            }
+                        }
+                        Handler::GroupResponse => {
+                            if let Err(e) = ctx.group_sync.on_response(topic, msg) {
+                                ctx.errors.record(&e);
+                            }
+                        }
+                        Handler::DeadLetterDump => {
+                            info!("📮 Dump the dead letters");
+                            ctx.deadletters.dump(&mut ctx.outbound, &origin);
+                        }
+                        Handler::Loop(index) => loop_indexes.push(index),
+                        Handler::LoopAvailability(index) => availability_indexes.push(index),
+                        Handler::LoopCommand(index) => process_loop_command(topic, msg, index, ctx, &origin),
+                        Handler::Timers => {
+                            if let Err(e) = ctx.timers.on_message(topic, msg, now_millis()) {
+                                ctx.errors.record(&e);
+                            }
+                        }
+                        Handler::Action => process_action_message(topic, msg, ctx, &origin).await,
+                    }
+                }
+
+                if !availability_indexes.is_empty() {
+                    if let Some(device_topic) = device_topic_of(topic) {
+                        process_availability_message(topic, device_topic, msg, &availability_indexes, ctx);
                     }
                 }
-            }
-            Event::Incoming(Incoming::ConnAck(connack)) => {
 
+                // The answer to the /get of the initialization gives the state, it's not a change to propagate
+                if !loop_indexes.is_empty() && reinit_device(topic, msg, ctx) {
+                    loop_indexes.clear();
+                }
+
+                if !loop_indexes.is_empty() {
+                    let coalesce = loop_indexes.iter()
+                        .filter_map(|i| ctx.all_loops.get(*i))
+                        .find_map(|lp| lp.find_device_by_topic(topic))
+                        .and_then(|dev| dev.as_ref().borrow().get_policy().coalesce);
+                    let from_ava = incoming_origin.as_ref().is_some_and(|o| o.is_ava());
+                    let message = HeldMessage { topic: topic.to_string(), msg: msg.to_string(), indexes: loop_indexes, origin: origin.clone(), from_ava };
+                    let held = coalesce.is_some_and(|window| ctx.coalescer.hold(&message, window, now_millis()));
+                    if !held {
+                        process_device_message(topic, msg, &message.indexes, ctx, &origin, from_ava).await;
+                    }
+                }
+                flush_outbound(client, ctx);
+            }
+            Event::Incoming(Incoming::ConnAck(_connack)) => {
+                // After a reconnection, the broker may have published our last will
+                ctx.status.publish_online(client);
+                resubscribe = !subscribe_again(client, ctx);
+                ctx.outbound.set_connected(true);
+                enforce_startup_policies(ctx);
+                flush_outbound(client, ctx);
             }
-            Event::Incoming(Incoming::PubAck(pubAck)) => {
+            Event::Incoming(Incoming::PubAck(_pub_ack)) => {
 
             }
             _ => {}
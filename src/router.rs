@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+
+use log::info;
+
+use crate::availability::availability_topic;
+use crate::bridge::BRIDGE_STATE_TOPIC;
+use crate::deadletter::dump_request_topic;
+use crate::group_sync::GROUP_RESPONSE_FILTER;
+use crate::groups::{BRIDGE_DEVICES_TOPIC, BRIDGE_GROUPS_TOPIC};
+use crate::loop_admin::loop_set_topic;
+use crate::loops::HardLoop;
+
+///
+/// What AVA does with a message received on a topic
+///
+#[derive(Debug, Clone, PartialEq)]
+pub (crate) enum Handler {
+    // Index of the loop in the loop list
+    Loop(usize),
+    LoopAvailability(usize),
+    LoopCommand(usize),
+    // The topic drives some timers
+    Timers,
+    // Actions of a remote
+    Action,
+    BridgeState,
+    BridgeDevices,
+    BridgeGroups,
+    GroupResponse,
+    DeadLetterDump,
+}
+
+#[derive(Debug)]
+struct Node<T> {
+    children: HashMap<String, Node<T>>,
+    handlers: Vec<T>,
+}
+
+impl <T> Node<T> {
+    fn new() -> Self {
+        Self {
+            children: HashMap::new(),
+            handlers: vec![],
+        }
+    }
+}
+
+///
+/// Trie of topic filters, each level of a filter is a node.
+/// Supports the MQTT wildcards : + for one level, # for all the remaining levels.
+///
+#[derive(Debug)]
+pub (crate) struct TopicRouter<T> {
+    root: Node<T>,
+}
+
+impl <T: Clone + PartialEq> TopicRouter<T> {
+    pub (crate) fn new() -> Self {
+        Self {
+            root: Node::new(),
+        }
+    }
+
+    pub (crate) fn add(&mut self, filter: &str, handler: T) {
+        let mut node = &mut self.root;
+        for level in filter.split('/') {
+            node = node.children.entry(level.to_string()).or_insert_with(Node::new);
+        }
+        if !node.handlers.contains(&handler) {
+            node.handlers.push(handler);
+        }
+    }
+
+    /// All the handlers whose filter matches the topic, each one once
+    pub (crate) fn route(&self, topic: &str) -> Vec<T> {
+        let levels: Vec<&str> = topic.split('/').collect();
+        let mut handlers = vec![];
+        // Wildcards don't match the topics starting with $ (ex : $SYS), the other levels may start with $
+        Self::collect(&self.root, &levels, !topic.starts_with('$'), &mut handlers);
+        handlers
+    }
+
+    fn collect(node: &Node<T>, levels: &[&str], wildcard_allowed: bool, handlers: &mut Vec<T>) {
+        // '#' also matches the parent level : sport/# matches sport
+        if wildcard_allowed {
+            if let Some(child) = node.children.get("#") {
+                Self::push_all(&child.handlers, handlers);
+            }
+        }
+
+        let (level, rest) = match levels.split_first() {
+            None => {
+                Self::push_all(&node.handlers, handlers);
+                return;
+            }
+            Some(split) => split
+        };
+
+        if let Some(child) = node.children.get(*level) {
+            Self::collect(child, rest, true, handlers);
+        }
+        if wildcard_allowed {
+            if let Some(child) = node.children.get("+") {
+                Self::collect(child, rest, true, handlers);
+            }
+        }
+    }
+
+    fn push_all(from: &[T], to: &mut Vec<T>) {
+        for h in from {
+            if !to.contains(h) {
+                to.push(h.clone());
+            }
+        }
+    }
+}
+
+///
+/// Route the topics of the loop devices to their loops, the topics of the timer rules and the remotes, once, from the configuration
+///
+pub (crate) fn build_router(all_loops: &[HardLoop], timer_topics: &[String], remotes: &[String], deadletter_topic: &str) -> TopicRouter<Handler> {
+    let mut router = TopicRouter::new();
+    router.add(BRIDGE_STATE_TOPIC, Handler::BridgeState);
+    router.add(BRIDGE_DEVICES_TOPIC, Handler::BridgeDevices);
+    router.add(BRIDGE_GROUPS_TOPIC, Handler::BridgeGroups);
+    router.add(GROUP_RESPONSE_FILTER, Handler::GroupResponse);
+    router.add(&dump_request_topic(deadletter_topic), Handler::DeadLetterDump);
+    for (index, lp) in all_loops.iter().enumerate() {
+        router.add(&loop_set_topic(&lp.name), Handler::LoopCommand(index));
+        for dev in &lp.devices {
+            let topic = dev.as_ref().borrow().get_topic();
+            info!("Route [{}] to loop [{}]", &topic, &lp.name);
+            router.add(&availability_topic(&topic), Handler::LoopAvailability(index));
+            router.add(&topic, Handler::Loop(index));
+        }
+    }
+    for topic in timer_topics {
+        info!("Route [{}] to the timers", topic);
+        router.add(topic, Handler::Timers);
+    }
+    for topic in remotes {
+        info!("Route [{}] to the actions", topic);
+        router.add(topic, Handler::Action);
+    }
+    router
+}
+
+/// zigbee2mqtt/hall_lamp -> zigbee2mqtt/+
+pub (crate) fn wildcard_filter(topic: &str) -> String {
+    match topic.rsplit_once('/') {
+        None => "+".to_string(),
+        Some((base, _)) => format!("{}/+", base),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn router(filters: &[(&str, u32)]) -> TopicRouter<u32> {
+        let mut router = TopicRouter::new();
+        for (filter, handler) in filters {
+            router.add(filter, *handler);
+        }
+        router
+    }
+
+    #[test]
+    fn exact_and_single_level_wildcard() {
+        let r = router(&[("zigbee2mqtt/hall_lamp", 1), ("zigbee2mqtt/+", 2)]);
+        assert_eq!(r.route("zigbee2mqtt/hall_lamp"), vec![1, 2]);
+        assert_eq!(r.route("zigbee2mqtt/kitchen_lamp"), vec![2]);
+        assert!(r.route("zigbee2mqtt/hall_lamp/availability").is_empty());
+        assert!(r.route("zigbee2mqtt").is_empty());
+    }
+
+    #[test]
+    fn multi_level_wildcard_matches_the_parent_level() {
+        let r = router(&[("zigbee2mqtt/bridge/response/group/#", 1)]);
+        assert_eq!(r.route("zigbee2mqtt/bridge/response/group/members/add"), vec![1]);
+        assert_eq!(r.route("zigbee2mqtt/bridge/response/group"), vec![1]);
+        assert!(r.route("zigbee2mqtt/bridge/response").is_empty());
+    }
+
+    #[test]
+    fn wildcards_skip_the_dollar_topics() {
+        let r = router(&[("#", 1), ("+/broker", 2), ("$SYS/broker", 3)]);
+        assert_eq!(r.route("$SYS/broker"), vec![3]);
+        assert_eq!(r.route("ava/broker"), vec![1, 2]);
+    }
+
+    #[test]
+    fn dollar_below_the_first_level() {
+        let r = router(&[("zigbee2mqtt/+", 1), ("zigbee2mqtt/#", 2)]);
+        assert_eq!(r.route("zigbee2mqtt/$foo"), vec![2, 1]);
+    }
+
+    #[test]
+    fn each_handler_once() {
+        let r = router(&[("zigbee2mqtt/hall_lamp", 1), ("zigbee2mqtt/+", 1), ("zigbee2mqtt/#", 1), ("zigbee2mqtt/hall_lamp", 1)]);
+        assert_eq!(r.route("zigbee2mqtt/hall_lamp"), vec![1]);
+    }
+}
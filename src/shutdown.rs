@@ -0,0 +1,119 @@
+use std::fmt;
+use std::time::Duration;
+
+use log::{error, info, warn};
+use rumqttc::Outgoing;
+use rumqttc::v5::{AsyncClient, Event, EventLoop};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time;
+
+use crate::ava_status::AvaStatus;
+use crate::error::AvaError;
+use crate::outbound::OutboundQueue;
+use crate::state_store::{AvaState, save_state};
+use crate::Params;
+
+pub (crate) const EXIT_OK: i32 = 0;
+pub (crate) const EXIT_INIT_FAILED: i32 = 1;
+pub (crate) const EXIT_CONNECTION_LOST: i32 = 2;
+pub (crate) const EXIT_CONFIG_ERROR: i32 = 3;
+
+/// Why AVA leaves the processing loop
+#[derive(Debug, Clone)]
+pub (crate) enum ShutdownReason {
+    Signal(&'static str),
+    InitFailed(AvaError),
+    ConnectionLost(String),
+}
+
+impl ShutdownReason {
+    pub (crate) fn exit_code(&self) -> i32 {
+        match self {
+            ShutdownReason::Signal(_) => EXIT_OK,
+            ShutdownReason::InitFailed(_) => EXIT_INIT_FAILED,
+            ShutdownReason::ConnectionLost(_) => EXIT_CONNECTION_LOST,
+        }
+    }
+}
+
+impl fmt::Display for ShutdownReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShutdownReason::Signal(name) => write!(f, "signal {}", name),
+            ShutdownReason::InitFailed(e) => write!(f, "initialization failed, {}", e),
+            ShutdownReason::ConnectionLost(e) => write!(f, "connection lost, {}", e),
+        }
+    }
+}
+
+///
+/// Wait for SIGTERM (systemd stop) or SIGINT (Ctrl-C)
+///
+pub (crate) async fn wait_for_signal() -> ShutdownReason {
+    let mut sigterm = match signal(SignalKind::terminate()) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("💀 Cannot listen to SIGTERM, e={}", e);
+            let _ = tokio::signal::ctrl_c().await;
+            return ShutdownReason::Signal("SIGINT");
+        }
+    };
+    tokio::select! {
+        _ = sigterm.recv() => ShutdownReason::Signal("SIGTERM"),
+        _ = tokio::signal::ctrl_c() => ShutdownReason::Signal("SIGINT"),
+    }
+}
+
+///
+/// Persist the state, say goodbye on the bus and leave the broker once the outbound queue is empty.
+/// The commands that could not be handed to the client stay in the outbound file, if any.
+///
+pub (crate) async fn shutdown(client: &AsyncClient, eventloop: &mut EventLoop,
+                              state: &AvaState,
+                              params: &Params, status: &AvaStatus, outbound: &mut OutboundQueue, reason: &ShutdownReason) {
+    info!("🛑 Shutdown AVA, reason={}", reason);
+
+    if let Err(e) = save_state(&params.state_file, state) {
+        error!("💀 Cannot save the state, e={}", e);
+    }
+
+    if let ShutdownReason::ConnectionLost(_) = reason {
+        // No broker to talk to
+        outbound.save();
+        return;
+    }
+
+    outbound.flush(client);
+    if outbound.len() > 0 {
+        warn!("📤 [{}] commands not sent", outbound.len());
+    }
+    outbound.save();
+
+    status.publish_offline(client);
+
+    // The disconnect request is queued behind the pending publishes, so it's sent once they are all out.
+    if let Err(e) = client.try_disconnect() {
+        warn!("Cannot request the disconnection, e={:?}", e);
+        return;
+    }
+
+    let drain = async {
+        loop {
+            match eventloop.poll().await {
+                Ok(Event::Outgoing(Outgoing::Disconnect)) => {
+                    info!("Disconnected from the broker");
+                    break;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("Connection closed while draining, e={}", e);
+                    break;
+                }
+            }
+        }
+    };
+
+    if time::timeout(Duration::from_secs(params.shutdown_timeout), drain).await.is_err() {
+        warn!("Outbound queue not drained after {}s, leave anyway", params.shutdown_timeout);
+    }
+}
@@ -0,0 +1,160 @@
+use std::cell::RefCell;
+use std::ops::Deref;
+use std::sync::Arc;
+
+use log::info;
+
+use crate::device_message::DeviceMessage;
+use crate::dyn_device::DynDevice;
+use crate::loops::HardLoop;
+use crate::manual_override::is_overridden;
+use crate::origin::Origin;
+use crate::processing::AvaContext;
+
+///
+/// What a loop does when AVA starts (or reconnects) and its devices don't agree
+///
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub (crate) enum StartupPolicy {
+    // Keep the devices as they are
+    #[default]
+    Leave,
+    // Align the other devices on this one (name of the device)
+    Leader(&'static str),
+    // Align the other devices on the state most of them already share
+    Majority,
+}
+
+/// The available devices of the loop with a readable state
+fn known_states(lp: &HardLoop) -> Vec<(Arc<RefCell<dyn DynDevice>>, Box<dyn DeviceMessage>)> {
+    let mut states = vec![];
+    for dev in &lp.devices {
+        let message = {
+            let dd = dev.as_ref().borrow();
+            if !dd.is_available() {
+                continue;
+            }
+            match dd.last_message() {
+                Ok(m) => m,
+                Err(_) => continue,
+            }
+        };
+        states.push((dev.clone(), message));
+    }
+    states
+}
+
+pub (crate) fn device_name(dd: &dyn DynDevice) -> String {
+    let topic = dd.get_topic();
+    topic.rsplit('/').next().unwrap_or(&topic).to_string()
+}
+
+///
+/// The device whose state converted for the others matches the most of them
+///
+fn majority_leader(states: &[(Arc<RefCell<dyn DynDevice>>, Box<dyn DeviceMessage>)]) -> Option<usize> {
+    let mut best: Option<(usize, usize)> = None;
+    for (i, (_, candidate)) in states.iter().enumerate() {
+        let mut agreements = 0;
+        for (j, (other, other_message)) in states.iter().enumerate() {
+            if i == j {
+                continue;
+            }
+            let od = other.as_ref().borrow();
+            let converted = od.to_local(candidate, other_message);
+            if let (Ok(a), Ok(b)) = (converted.to_json(), other_message.to_json()) {
+                if od.get_policy().comparison.same_state(&a, &b) {
+                    agreements += 1;
+                }
+            }
+        }
+        if best.is_none_or(|(_, count)| agreements > count) {
+            best = Some((i, agreements));
+        }
+    }
+    best.map(|(i, _)| i)
+}
+
+///
+/// Apply the startup policy of every loop : the followers consume the state of the leader, like a regular propagation
+///
+pub (crate) fn enforce_startup_policies(ctx: &mut AvaContext) {
+    let origin = Origin::new_cause(&ctx.status.client_id);
+    for lp in &ctx.all_loops {
+        if lp.startup_policy == StartupPolicy::Leave || !lp.enabled {
+            continue;
+        }
+        let states = known_states(lp);
+        let leader = match lp.startup_policy {
+            StartupPolicy::Leave => None,
+            StartupPolicy::Leader(name) => states.iter().position(|(dev, _)| device_name(dev.as_ref().borrow().deref()) == name),
+            StartupPolicy::Majority => majority_leader(&states),
+        };
+        let (leader_dev, leader_message) = match leader {
+            None => {
+                info!("⚖ No leader to align loop [{}] with {:?}", &lp.name, lp.startup_policy);
+                continue;
+            }
+            Some(i) => &states[i],
+        };
+        let leader_topic = leader_dev.as_ref().borrow().get_topic();
+        info!("⚖ Align loop [{}] on [{}] ({:?})", &lp.name, &leader_topic.to_uppercase(), lp.startup_policy);
+        if let Ok(json_message) = leader_message.to_json() {
+            lp.last_state.replace(Some((leader_topic.clone(), json_message)));
+        }
+
+        let loop_origin = origin.for_loop(&lp.name);
+        for (dev, _) in &states {
+            let dd = dev.as_ref().borrow();
+            if dd.get_topic() == leader_topic || is_overridden(dd.deref()) {
+                continue;
+            }
+            if let Err(e) = dd.consume_message(leader_message, &mut ctx.outbound, &loop_origin, &lp.command_policy_for(dd.deref())) {
+                ctx.errors.record(&e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device_message::LampRGB;
+    use crate::hall_lamp::HallLampDevice;
+    use crate::kitchen_lamp::KitchenLampDevice;
+
+    type State = (Arc<RefCell<dyn DynDevice>>, Box<dyn DeviceMessage>);
+
+    fn lamp(index: usize, json: &str) -> State {
+        let device: Arc<RefCell<dyn DynDevice>> = if index.is_multiple_of(2) {
+            Arc::new(RefCell::new(KitchenLampDevice::new()))
+        } else {
+            Arc::new(RefCell::new(HallLampDevice::new()))
+        };
+        (device, Box::new(LampRGB::from_json(json).unwrap()))
+    }
+
+    fn states(jsons: &[&str]) -> Vec<State> {
+        jsons.iter().enumerate().map(|(i, json)| lamp(i, json)).collect()
+    }
+
+    const ON: &str = r#"{"state":"ON","brightness":200,"color_temp":300}"#;
+    const OFF: &str = r#"{"state":"OFF","brightness":200,"color_temp":300}"#;
+
+    #[test]
+    fn the_state_most_devices_share_leads() {
+        assert_eq!(majority_leader(&states(&[OFF, ON, ON])), Some(1));
+        assert_eq!(majority_leader(&states(&[ON, OFF, ON, OFF, OFF])), Some(1));
+    }
+
+    #[test]
+    fn the_first_device_leads_a_tie() {
+        assert_eq!(majority_leader(&states(&[OFF, ON])), Some(0));
+        assert_eq!(majority_leader(&states(&[ON])), Some(0));
+    }
+
+    #[test]
+    fn no_device_no_leader() {
+        assert_eq!(majority_leader(&states(&[])), None);
+    }
+}
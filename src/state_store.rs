@@ -0,0 +1,90 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::ops::Deref;
+use std::sync::Arc;
+
+use log::{info, warn};
+use serde_derive::*;
+
+use crate::dyn_device::DynDevice;
+use crate::error::AvaError;
+use crate::loops::HardLoop;
+
+///
+/// What AVA keeps on disk between two runs
+///
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub (crate) struct AvaState {
+    // Last known message of each device, by topic
+    pub devices: HashMap<String, String>,
+    // Enabled flag of each loop, by name
+    #[serde(default)]
+    pub loops: HashMap<String, bool>,
+    // Deadline of the running timers, by name, when they are persisted
+    #[serde(default)]
+    pub timers: HashMap<String, u64>,
+}
+
+impl AvaState {
+
+    /// Take a snapshot of the last message of every device in the repository and of the loop flags
+    pub (crate) fn from_repo(device_repo: &HashMap<String, Arc<RefCell<dyn DynDevice>>>, all_loops: &[HardLoop]) -> Self {
+        let mut devices = HashMap::new();
+        for dev in device_repo.values() {
+            let dd = dev.as_ref().borrow();
+            let lk = dd.get_lock();
+            let last = lk.as_ref().borrow().deref().last_object_message.clone();
+            if !last.is_empty() {
+                devices.insert(dd.get_topic(), last);
+            }
+        }
+        let loops = all_loops.iter().map(|lp| (lp.name.clone(), lp.enabled)).collect();
+        Self { devices, loops, timers: HashMap::new() }
+    }
+
+    /// Put the saved messages back into the devices, the initialization stage will refresh them later
+    pub (crate) fn restore(&self, device_repo: &HashMap<String, Arc<RefCell<dyn DynDevice>>>) {
+        for dev in device_repo.values() {
+            let dd = dev.as_ref().borrow();
+            if let Some(last) = self.devices.get(&dd.get_topic()) {
+                info!("💾 Restore device [{}], with message <{}>", &dd.get_topic().to_uppercase(), last);
+                dd.get_lock().borrow_mut().replace(last.clone());
+            }
+        }
+    }
+
+    /// A loop disabled before the restart stays disabled
+    pub (crate) fn restore_loops(&self, all_loops: &mut [HardLoop]) {
+        for lp in all_loops.iter_mut() {
+            if let Some(enabled) = self.loops.get(&lp.name) {
+                if !*enabled {
+                    info!("💾 Restore loop [{}], disabled", &lp.name);
+                }
+                lp.enabled = *enabled;
+            }
+        }
+    }
+}
+
+pub (crate) fn save_state(path: &str, state: &AvaState) -> Result<(), AvaError> {
+    let data = serde_json::to_string_pretty(state).map_err(|e| AvaError::State(e.to_string()))?;
+    fs::write(path, data).map_err(|e| AvaError::State(format!("cannot write [{}], {}", path, e)))?;
+    info!("💾 State saved in [{}]", path);
+    Ok(())
+}
+
+pub (crate) fn load_state(path: &str) -> AvaState {
+    match fs::read_to_string(path) {
+        Ok(data) => {
+            serde_json::from_str(&data).unwrap_or_else(|e| {
+                warn!("Cannot read the state file [{}], start with an empty state, e={}", path, e);
+                AvaState::default()
+            })
+        }
+        Err(_) => {
+            info!("No state file [{}], start with an empty state", path);
+            AvaState::default()
+        }
+    }
+}
@@ -0,0 +1,358 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::info;
+use serde_derive::*;
+use serde_json::{json, Value};
+
+use crate::ava_status::AVA_EVENT_TOPIC;
+use crate::device_repo::find_device;
+use crate::dyn_device::DynDevice;
+use crate::error::AvaError;
+use crate::hall_lamp::HALL_LAMP;
+use crate::origin::Origin;
+use crate::outbound::OutboundQueue;
+use crate::policy::CommandPolicy;
+
+pub (crate) const HALL_AUTO_OFF: &str = "HALL_AUTO_OFF";
+
+// No device of the repository, only the rules listen to it
+const HALL_MOTION_TOPIC: &str = "zigbee2mqtt/hall_motion";
+const HALL_AUTO_OFF_DELAY: Duration = Duration::from_secs(600);
+
+pub (crate) fn build_timer_rules(device_repo: &HashMap<String, Arc<RefCell<dyn DynDevice>>>) -> Result<Vec<TimerRule>, AvaError> {
+    let hall_lamp = find_device(device_repo, HALL_LAMP)?;
+    let hall_topic = hall_lamp.as_ref().borrow().get_topic();
+
+    // Turn the hall lamp off 10 minutes after it was turned on, unless motion is seen
+    let hall_auto_off = TimerRule::new(HALL_AUTO_OFF.to_string(), HALL_AUTO_OFF_DELAY, hall_lamp, json!({"state": "OFF"}))
+        .start_on(&hall_topic, "state", json!("ON"))
+        .extend_on(HALL_MOTION_TOPIC, "occupancy", json!(true))
+        .cancel_on(&hall_topic, "state", json!("OFF"));
+
+    Ok(vec![hall_auto_off])
+}
+
+///
+/// What an event does to its timer.
+/// Start reacts when the field takes the value, so a running timer is restarted only by a new transition.
+/// Extend and cancel react to every message with the value, when the timer is running.
+///
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub (crate) enum TimerOp {
+    Start,
+    Extend,
+    Cancel,
+}
+
+///
+/// A field of the json published on a topic, ex : zigbee2mqtt/hall_motion, occupancy = true
+///
+#[derive(Debug, Clone, PartialEq)]
+pub (crate) struct EventMatch {
+    pub topic: String,
+    pub field: &'static str,
+    pub value: Value,
+}
+
+impl EventMatch {
+    fn matches(&self, state: Option<&Value>) -> bool {
+        match (state.and_then(|s| s.get(self.field)), &self.value) {
+            // Zigbee2mqtt is not consistent on the case of ON/OFF
+            (Some(Value::String(a)), Value::String(b)) => a.eq_ignore_ascii_case(b),
+            (Some(a), b) => a == b,
+            (None, _) => false,
+        }
+    }
+}
+
+///
+/// A named timer : the events start, extend or cancel it, the device gets the payload when it fires
+///
+#[derive(Clone)]
+pub (crate) struct TimerRule {
+    pub name: String,
+    pub duration: Duration,
+    pub device: Arc<RefCell<dyn DynDevice>>,
+    pub payload: Value,
+    pub events: Vec<(TimerOp, EventMatch)>,
+}
+
+impl TimerRule {
+    fn new(name: String, duration: Duration, device: Arc<RefCell<dyn DynDevice>>, payload: Value) -> Self {
+        Self {
+            name,
+            duration,
+            device,
+            payload,
+            events: vec![],
+        }
+    }
+
+    fn on(mut self, op: TimerOp, topic: &str, field: &'static str, value: Value) -> Self {
+        self.events.push((op, EventMatch { topic: topic.to_string(), field, value }));
+        self
+    }
+
+    fn start_on(self, topic: &str, field: &'static str, value: Value) -> Self {
+        self.on(TimerOp::Start, topic, field, value)
+    }
+
+    fn extend_on(self, topic: &str, field: &'static str, value: Value) -> Self {
+        self.on(TimerOp::Extend, topic, field, value)
+    }
+
+    fn cancel_on(self, topic: &str, field: &'static str, value: Value) -> Self {
+        self.on(TimerOp::Cancel, topic, field, value)
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct TimerEvent {
+    event: String,
+    timer: String,
+    device: String,
+    payload: String,
+}
+
+///
+/// The timers of the rules, driven by the messages of their topics and fired by the main loop.
+/// The deadlines are epoch millis, so they can be saved and survive a restart.
+///
+pub (crate) struct Timers {
+    rules: Vec<TimerRule>,
+    // Deadline of the running timers, by name
+    pending: HashMap<String, u64>,
+    // Last known state of the topics of the rules, the messages may only carry the fields that changed
+    states: HashMap<String, Value>,
+}
+
+impl Timers {
+    pub (crate) fn new(rules: Vec<TimerRule>) -> Self {
+        Self {
+            rules,
+            pending: HashMap::new(),
+            states: HashMap::new(),
+        }
+    }
+
+    /// The topics the rules listen to, each once
+    pub (crate) fn topics(&self) -> Vec<String> {
+        let mut topics: Vec<String> = vec![];
+        for (_, event) in self.rules.iter().flat_map(|r| r.events.iter()) {
+            if !topics.contains(&event.topic) {
+                topics.push(event.topic.clone());
+            }
+        }
+        topics
+    }
+
+    pub (crate) fn pending(&self) -> &HashMap<String, u64> {
+        &self.pending
+    }
+
+    /// Put back the timers saved by the previous run, the overdue ones fire right away
+    pub (crate) fn restore(&mut self, pending: &HashMap<String, u64>) {
+        for (name, deadline) in pending {
+            if self.rules.iter().any(|r| &r.name == name) {
+                info!("💾 Restore timer [{}], deadline [{}]", name, deadline);
+                self.pending.insert(name.clone(), *deadline);
+            }
+        }
+    }
+
+    ///
+    /// Start, extend or cancel the timers of the rules listening to the topic
+    ///
+    pub (crate) fn on_message(&mut self, topic: &str, msg: &str, now: u64) -> Result<(), AvaError> {
+        let incoming: Value = serde_json::from_str(msg)?;
+        let previous = self.states.get(topic).cloned();
+        let mut state = previous.clone().unwrap_or_else(|| json!({}));
+        if let (Value::Object(s), Value::Object(i)) = (&mut state, incoming) {
+            s.extend(i);
+        }
+
+        for rule in &self.rules {
+            for (op, event) in rule.events.iter().filter(|(_, e)| e.topic == topic) {
+                if !event.matches(Some(&state)) {
+                    continue;
+                }
+                let running = self.pending.contains_key(&rule.name);
+                match op {
+                    TimerOp::Start if !event.matches(previous.as_ref()) => {
+                        info!("⏲ Start timer [{}] for {}s", &rule.name, rule.duration.as_secs());
+                        self.pending.insert(rule.name.clone(), now + rule.duration.as_millis() as u64);
+                    }
+                    TimerOp::Extend if running => {
+                        info!("⏲ Extend timer [{}] for {}s", &rule.name, rule.duration.as_secs());
+                        self.pending.insert(rule.name.clone(), now + rule.duration.as_millis() as u64);
+                    }
+                    TimerOp::Cancel if running => {
+                        info!("⏲ Cancel timer [{}]", &rule.name);
+                        self.pending.remove(&rule.name);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        self.states.insert(topic.to_string(), state);
+        Ok(())
+    }
+
+    ///
+    /// Start, extend or cancel the timer without an event of its rule (ex : from a remote)
+    ///
+    pub (crate) fn apply(&mut self, name: &str, op: TimerOp, now: u64) -> Result<(), AvaError> {
+        let rule = self.rules.iter().find(|r| r.name == name)
+            .ok_or_else(|| AvaError::Config(format!("unknown timer [{}]", name)))?;
+        let running = self.pending.contains_key(name);
+        info!("⏲ {:?} timer [{}]", op, name);
+        match op {
+            TimerOp::Start => { self.pending.insert(rule.name.clone(), now + rule.duration.as_millis() as u64); }
+            TimerOp::Extend if running => { self.pending.insert(rule.name.clone(), now + rule.duration.as_millis() as u64); }
+            TimerOp::Extend => {}
+            TimerOp::Cancel => { self.pending.remove(name); }
+        }
+        Ok(())
+    }
+
+    /// Time left before the next timer fires, none when no timer is running
+    pub (crate) fn next_delay(&self, now: u64) -> Option<Duration> {
+        self.pending.values().min().map(|deadline| Duration::from_millis(deadline.saturating_sub(now)))
+    }
+
+    ///
+    /// Fire the timers that are due : their device consumes the payload, like a message of its loop.
+    /// Return the failures by device topic.
+    ///
+    pub (crate) fn fire_due(&mut self, now: u64, outbound: &mut OutboundQueue, origin: &Origin) -> Vec<(String, AvaError)> {
+        let mut errors = vec![];
+        let due: Vec<String> = self.pending.iter().filter(|(_, d)| **d <= now).map(|(n, _)| n.clone()).collect();
+        for name in due {
+            self.pending.remove(&name);
+            let rule = match self.rules.iter().find(|r| r.name == name) {
+                None => continue,
+                Some(rule) => rule,
+            };
+            let dd1 = rule.device.as_ref().borrow();
+            let dd = dd1.deref();
+            let payload = rule.payload.to_string();
+            info!("⏰ Timer [{}] fired, send <{}> to {}", &name, &payload, &dd.get_topic().to_uppercase());
+            let fired = dd.merge_incoming(&payload)
+                .and_then(|merged| dd.from_json_to_local(&merged))
+                .and_then(|message| dd.consume_message(&message, outbound, origin, &dd.get_policy().command));
+            if let Err(e) = fired {
+                errors.push((dd.get_topic(), e));
+                continue;
+            }
+            let event = TimerEvent {
+                event: "timer".to_string(),
+                timer: name.clone(),
+                device: dd.get_topic(),
+                payload,
+            };
+            outbound.push_json(AVA_EVENT_TOPIC, &event, &CommandPolicy::default(), origin);
+        }
+        errors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hall_lamp::HallLampDevice;
+
+    const LAMP: &str = "zigbee2mqtt/hall_lamp";
+    const MOTION: &str = "zigbee2mqtt/hall_motion";
+    const TIMER: &str = "AUTO_OFF";
+    const DURATION: u64 = 600_000;
+
+    fn timers() -> (Timers, Arc<RefCell<dyn DynDevice>>) {
+        let lamp: Arc<RefCell<dyn DynDevice>> = Arc::new(RefCell::new(HallLampDevice::new()));
+        let rule = TimerRule::new(TIMER.to_string(), Duration::from_millis(DURATION), lamp.clone(), json!({"state": "OFF"}))
+            .start_on(LAMP, "state", json!("ON"))
+            .extend_on(MOTION, "occupancy", json!(true))
+            .cancel_on(LAMP, "state", json!("OFF"));
+        (Timers::new(vec![rule]), lamp)
+    }
+
+    fn deadline(timers: &Timers) -> Option<u64> {
+        timers.pending().get(TIMER).copied()
+    }
+
+    #[test]
+    fn start_only_on_a_transition() {
+        let (mut timers, _) = timers();
+        timers.on_message(LAMP, r#"{"state":"ON"}"#, 1_000).unwrap();
+        assert_eq!(deadline(&timers), Some(1_000 + DURATION));
+        // Still ON, the brightness changed : not a new start
+        timers.on_message(LAMP, r#"{"brightness":120}"#, 5_000).unwrap();
+        timers.on_message(LAMP, r#"{"state":"on"}"#, 6_000).unwrap();
+        assert_eq!(deadline(&timers), Some(1_000 + DURATION));
+    }
+
+    #[test]
+    fn extend_and_cancel_only_a_running_timer() {
+        let (mut timers, _) = timers();
+        timers.on_message(MOTION, r#"{"occupancy":true}"#, 1_000).unwrap();
+        timers.on_message(LAMP, r#"{"state":"OFF"}"#, 1_000).unwrap();
+        assert_eq!(deadline(&timers), None);
+
+        timers.on_message(LAMP, r#"{"state":"ON"}"#, 2_000).unwrap();
+        timers.on_message(MOTION, r#"{"occupancy":true}"#, 3_000).unwrap();
+        assert_eq!(deadline(&timers), Some(3_000 + DURATION));
+        timers.on_message(MOTION, r#"{"occupancy":false}"#, 4_000).unwrap();
+        assert_eq!(deadline(&timers), Some(3_000 + DURATION));
+
+        timers.on_message(LAMP, r#"{"state":"OFF"}"#, 5_000).unwrap();
+        assert_eq!(deadline(&timers), None);
+    }
+
+    #[test]
+    fn apply_without_event() {
+        let (mut timers, _) = timers();
+        timers.apply(TIMER, TimerOp::Extend, 1_000).unwrap();
+        assert_eq!(deadline(&timers), None);
+        timers.apply(TIMER, TimerOp::Start, 1_000).unwrap();
+        timers.apply(TIMER, TimerOp::Extend, 2_000).unwrap();
+        assert_eq!(deadline(&timers), Some(2_000 + DURATION));
+        timers.apply(TIMER, TimerOp::Cancel, 3_000).unwrap();
+        assert_eq!(deadline(&timers), None);
+        assert!(timers.apply("UNKNOWN", TimerOp::Start, 3_000).is_err());
+    }
+
+    #[test]
+    fn restore_only_the_known_timers() {
+        let (mut timers, _) = timers();
+        let saved = HashMap::from([(TIMER.to_string(), 42_000), ("GONE".to_string(), 43_000)]);
+        timers.restore(&saved);
+        assert_eq!(timers.pending().len(), 1);
+        assert_eq!(deadline(&timers), Some(42_000));
+        assert_eq!(timers.next_delay(40_000), Some(Duration::from_millis(2_000)));
+        assert_eq!(timers.next_delay(50_000), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn fire_the_due_timers_only() {
+        let (mut timers, lamp) = timers();
+        let mut outbound = OutboundQueue::new(10, None);
+        let origin = Origin::new_cause("test");
+        lamp.borrow_mut().init(LAMP, r#"{"state":"ON","brightness":200,"color_temp":300}"#);
+        timers.apply(TIMER, TimerOp::Start, 1_000).unwrap();
+
+        assert!(timers.fire_due(DURATION, &mut outbound, &origin).is_empty());
+        assert_eq!(deadline(&timers), Some(1_000 + DURATION));
+
+        assert!(timers.fire_due(1_000 + DURATION, &mut outbound, &origin).is_empty());
+        assert_eq!(deadline(&timers), None);
+        assert_eq!(timers.next_delay(1_000 + DURATION), None);
+        // The lamp was sent the payload and waits for its echo
+        let lk = lamp.as_ref().borrow().get_lock();
+        let dev_lock = lk.as_ref().borrow();
+        assert_eq!(dev_lock.count_locks, 1);
+        assert!(dev_lock.last_object_message.contains(r#""state":"OFF""#));
+    }
+}
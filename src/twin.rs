@@ -0,0 +1,114 @@
+use std::time::Duration;
+
+use log::{info, warn};
+use serde_derive::*;
+
+use crate::ava_status::AVA_EVENT_TOPIC;
+use crate::dyn_device::DynDevice;
+use crate::origin::Origin;
+use crate::outbound::{now_millis, OutboundCommand, OutboundQueue};
+use crate::policy::CommandPolicy;
+
+///
+/// How long AVA waits for a device to report the state it was asked for, and how many times it asks again.
+/// The wait doubles after each attempt.
+///
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub (crate) struct ReconcilePolicy {
+    pub timeout: Duration,
+    pub max_retries: u32,
+}
+
+impl Default for ReconcilePolicy {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(3),
+            max_retries: 3,
+        }
+    }
+}
+
+///
+/// The state AVA asked a device to reach, with what is needed to ask again
+///
+#[derive(Debug, Clone)]
+pub (crate) struct DesiredState {
+    pub message: String,
+    pub policy: CommandPolicy,
+    pub origin: Origin,
+    pub attempts: u32,
+    // Epoch millis
+    pub deadline: u64,
+}
+
+impl DesiredState {
+    pub (crate) fn new(message: &str, policy: CommandPolicy, origin: &Origin, reconcile: &ReconcilePolicy) -> Self {
+        Self {
+            message: message.to_string(),
+            policy,
+            origin: origin.clone(),
+            attempts: 0,
+            deadline: now_millis() + reconcile.timeout.as_millis() as u64,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct StuckEvent {
+    event: String,
+    device: String,
+    desired: String,
+    reported: String,
+    attempts: u32,
+}
+
+///
+/// Compare the desired and the reported state of the device.
+/// Send the command again when the device has not converged in time, give up after the max retries with a "stuck" event.
+///
+pub (crate) fn reconcile(device: &dyn DynDevice, outbound: &mut OutboundQueue, now: u64) {
+    let lk = device.get_lock();
+    let mut dev_lock = lk.as_ref().borrow_mut();
+    let desired = match &dev_lock.desired {
+        None => return,
+        Some(desired) => desired.clone(),
+    };
+    let policy = device.get_policy();
+    let topic = device.get_topic();
+
+    if policy.comparison.same_state(&desired.message, &dev_lock.reported) {
+        info!("✅ Device {} reached its desired state after [{}] retries", &topic.to_uppercase(), desired.attempts);
+        dev_lock.desired = None;
+        return;
+    }
+    if now < desired.deadline {
+        return;
+    }
+
+    if desired.attempts >= policy.reconcile.max_retries {
+        warn!("🧱 Device {} is stuck, desired <{}>, reported <{}>", &topic.to_uppercase(), &desired.message, &dev_lock.reported);
+        let event = StuckEvent {
+            event: "stuck".to_string(),
+            device: topic.clone(),
+            desired: desired.message.clone(),
+            reported: dev_lock.reported.clone(),
+            attempts: desired.attempts,
+        };
+        outbound.push_json(AVA_EVENT_TOPIC, &event, &CommandPolicy::default(), &desired.origin);
+        dev_lock.desired = None;
+        dev_lock.release_locks();
+        return;
+    }
+
+    let attempts = desired.attempts + 1;
+    info!("🔁 Device {} has not reached <{}>, send it again (attempt {}/{})", &topic.to_uppercase(), &desired.message, attempts, policy.reconcile.max_retries);
+    // No new lock : the command before was lost, its lock waits for the echo of this one
+    outbound.push_latest(OutboundCommand::new(&format!("{}/set", &topic), &desired.message, &desired.policy, &desired.origin).with_locks(vec![topic.clone()]));
+    dev_lock.commanded_at = Some(now);
+    let backoff = policy.reconcile.timeout.as_millis() as u64 * 2u64.pow(attempts);
+    dev_lock.desired = Some(DesiredState {
+        attempts,
+        deadline: now + backoff,
+        ..desired
+    });
+}